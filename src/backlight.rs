@@ -1,15 +1,15 @@
 use std::{
     fs::{File, OpenOptions, self},
-    path::{PathBuf, Path},
+    path::PathBuf,
     time::Instant,
     io::Write,
     cmp::min,
 };
 use anyhow::{Result, anyhow};
-use input::event::{
-    Event, switch::{Switch, SwitchEvent, SwitchState},
-};
+use input::event::switch::SwitchState;
 use crate::config::Config;
+use crate::events::InputEvent;
+use crate::sysfs::{class_dir, read_attr};
 use crate::TIMEOUT_MS;
 
 const MAX_DISPLAY_BRIGHTNESS: u32 = 509;
@@ -18,16 +18,8 @@ const BRIGHTNESS_DIM_TIMEOUT: i32 = TIMEOUT_MS * 3; // should be a multiple of T
 const BRIGHTNESS_OFF_TIMEOUT: i32 = TIMEOUT_MS * 6; // should be a multiple of TIMEOUT_MS
 const DIMMED_BRIGHTNESS: u32 = 1;
 
-fn read_attr(path: &Path, attr: &str) -> u32 {
-    fs::read_to_string(path.join(attr))
-        .expect(&format!("Failed to read {attr}"))
-        .trim()
-        .parse::<u32>()
-        .expect(&format!("Failed to parse {attr}"))
-}
-
 fn find_backlight() -> Result<PathBuf> {
-    for entry in fs::read_dir("/sys/class/backlight/")? {
+    for entry in fs::read_dir(class_dir("backlight"))? {
         let entry = entry?;
         let file_name = entry.file_name();
         let name = file_name.to_string_lossy();
@@ -40,7 +32,7 @@ fn find_backlight() -> Result<PathBuf> {
 }
 
 fn find_display_backlight() -> Result<PathBuf> {
-    for entry in fs::read_dir("/sys/class/backlight/")? {
+    for entry in fs::read_dir(class_dir("backlight"))? {
         let entry = entry?;
         if ["apple-panel-bl", "gmux_backlight", "intel_backlight", "acpi_video0"].iter().any(|s| entry.file_name().to_string_lossy().contains(s)) {
             return Ok(entry.path());
@@ -49,8 +41,10 @@ fn find_display_backlight() -> Result<PathBuf> {
     Err(anyhow!("No Built-in Retina Display backlight device found"))
 }
 
-fn set_backlight(mut file: &File, value: u32) {
-    file.write(format!("{}\n", value).as_bytes()).unwrap();
+fn set_backlight(file: Option<&File>, value: u32) {
+    if let Some(mut file) = file {
+        file.write(format!("{}\n", value).as_bytes()).unwrap();
+    }
 }
 
 pub struct BacklightManager {
@@ -58,22 +52,32 @@ pub struct BacklightManager {
     max_bl: u32,
     current_bl: u32,
     lid_state: SwitchState,
-    bl_file: File,
-    display_bl_path: PathBuf
+    // both only missing when running offscreen, where the brightness is then only kept track of,
+    // and adaptive brightness follows ActiveBrightness
+    bl_file: Option<File>,
+    display_bl_path: Option<PathBuf>,
+    // set by clients, replaces ActiveBrightness until the daemon restarts
+    active_brightness: Option<u32>,
 }
 
 impl BacklightManager {
     pub fn new() -> BacklightManager {
-        let bl_path = find_backlight().unwrap();
-        let display_bl_path = find_display_backlight().unwrap();
-        let bl_file = OpenOptions::new().write(true).open(bl_path.join("brightness")).unwrap();
+        BacklightManager::with_devices(Some(find_backlight().unwrap()), Some(find_display_backlight().unwrap()))
+    }
+    // Uses the backlights if there are any, for running without a touch bar
+    pub fn offscreen() -> BacklightManager {
+        BacklightManager::with_devices(find_backlight().ok(), find_display_backlight().ok())
+    }
+    fn with_devices(bl_path: Option<PathBuf>, display_bl_path: Option<PathBuf>) -> BacklightManager {
+        let bl_file = bl_path.as_ref().map(|path| OpenOptions::new().write(true).open(path.join("brightness")).unwrap());
         BacklightManager {
             bl_file,
             lid_state: SwitchState::Off,
-            max_bl: read_attr(&bl_path, "max_brightness"),
-            current_bl: read_attr(&bl_path, "brightness"),
+            max_bl: bl_path.as_ref().map_or(MAX_TOUCH_BAR_BRIGHTNESS, |path| read_attr(path, "max_brightness")),
+            current_bl: bl_path.as_ref().map_or(0, |path| read_attr(path, "brightness")),
             last_active: Instant::now(),
-            display_bl_path
+            display_bl_path,
+            active_brightness: None,
         }
     }
     fn display_to_touchbar(display: u32, active_brightness: u32) -> u32 {
@@ -82,24 +86,18 @@ impl BacklightManager {
         let adjusted = (normalized.powf(0.5) * active_brightness as f64) as u32 + 1;
         adjusted.min(MAX_TOUCH_BAR_BRIGHTNESS) // Clamp the value to the maximum allowed brightness
     }
-    pub fn process_event(&mut self, event: &Event) {
+    pub fn process_event(&mut self, event: &InputEvent) {
         match event {
-            Event::Keyboard(_) | Event::Pointer(_) | Event::Gesture(_) | Event::Touch(_) => {
-                self.last_active = Instant::now();
-            },
-            Event::Switch(SwitchEvent::Toggle(toggle)) => {
-                match toggle.switch() {
-                    Some(Switch::Lid) => {
-                        self.lid_state = toggle.switch_state();
-                        println!("Lid Switch event: {:?}", self.lid_state);
-                        if toggle.switch_state() == SwitchState::Off {
-                            self.last_active = Instant::now();
-                        }
-                    }
-                    _ => {}
+            InputEvent::Lid { closed } => {
+                self.lid_state = if *closed { SwitchState::On } else { SwitchState::Off };
+                println!("Lid Switch event: {:?}", self.lid_state);
+                if !closed {
+                    self.last_active = Instant::now();
                 }
+            },
+            _ => {
+                self.last_active = Instant::now();
             }
-            _ => {}
         }
     }
     pub fn update_backlight(&mut self, cfg: &Config) {
@@ -107,10 +105,11 @@ impl BacklightManager {
         let new_bl = min(self.max_bl, if self.lid_state == SwitchState::On {
             0
         } else if since_last_active < BRIGHTNESS_DIM_TIMEOUT as u64 {
-            if cfg.adaptive_brightness {
-                BacklightManager::display_to_touchbar(read_attr(&self.display_bl_path, "brightness"), cfg.active_brightness)
-            } else {
-                cfg.active_brightness
+            match &self.display_bl_path {
+                Some(path) if cfg.adaptive_brightness => {
+                    BacklightManager::display_to_touchbar(read_attr(path, "brightness"), self.active_brightness(cfg))
+                },
+                _ => self.active_brightness(cfg)
             }
         } else if since_last_active < BRIGHTNESS_OFF_TIMEOUT as u64 {
             DIMMED_BRIGHTNESS
@@ -119,10 +118,19 @@ impl BacklightManager {
         });
         if self.current_bl != new_bl {
             self.current_bl = new_bl;
-            set_backlight(&self.bl_file, self.current_bl);
+            set_backlight(self.bl_file.as_ref(), self.current_bl);
         }
     }
+    pub fn set_active_brightness(&mut self, value: u32) {
+        self.active_brightness = Some(value.min(MAX_TOUCH_BAR_BRIGHTNESS));
+    }
+    pub fn active_brightness(&self, cfg: &Config) -> u32 {
+        self.active_brightness.unwrap_or(cfg.active_brightness)
+    }
     pub fn current_bl(&self) -> u32 {
         self.current_bl
     }
+    pub fn idle(&self) -> bool {
+        (Instant::now() - self.last_active).as_millis() >= BRIGHTNESS_DIM_TIMEOUT as u128
+    }
 }
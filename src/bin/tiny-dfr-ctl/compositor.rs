@@ -0,0 +1,260 @@
+use std::{
+    env,
+    io::{BufRead, BufReader, ErrorKind, Read, Write},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    process::Command,
+};
+use anyhow::{anyhow, Result};
+use serde_json::{json, Value};
+use crate::i3_ipc::{self, EVENT_BIT, GET_TREE, HEADER_LEN, SUBSCRIBE};
+
+// Tells which app is focused, as an app id or window class
+pub trait ContextProvider {
+    // Empty if no window is focused
+    fn current_focus(&mut self) -> Result<String>;
+    // Blocks until the focused app changes, and returns the new one
+    fn next_focus(&mut self) -> Result<String>;
+}
+
+// The IPC socket of sway or i3, if the session runs under either
+pub fn i3_socket_path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os("SWAYSOCK").or_else(|| env::var_os("I3SOCK")) {
+        return Some(PathBuf::from(path));
+    }
+    // i3 does not always export I3SOCK
+    let output = Command::new("i3").arg("--get-socketpath").output().ok()?;
+    let path = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if !output.status.success() || path.is_empty() {
+        return None;
+    }
+    Some(PathBuf::from(path))
+}
+
+// Picks the provider for the compositor the session runs under
+pub fn from_env() -> Result<Box<dyn ContextProvider>> {
+    if let Some(sig) = env::var_os("HYPRLAND_INSTANCE_SIGNATURE") {
+        return Ok(Box::new(HyprlandProvider::new(&sig.to_string_lossy())?));
+    }
+    if let Some(path) = i3_socket_path() {
+        return Ok(Box::new(I3Provider::new(&path)?));
+    }
+    Err(anyhow!("no supported compositor found, SWAYSOCK, I3SOCK and HYPRLAND_INSTANCE_SIGNATURE are unset"))
+}
+
+// sway and i3 share the same IPC protocol
+struct I3Provider {
+    commands: UnixStream,
+    events: UnixStream,
+    focus: String,
+}
+
+fn i3_send(stream: &mut UnixStream, msg_type: u32, payload: &str) -> Result<()> {
+    stream.write_all(&i3_ipc::encode(msg_type, payload))?;
+    Ok(())
+}
+
+fn i3_recv(stream: &mut UnixStream) -> Result<(u32, Value)> {
+    let mut header = [0u8; HEADER_LEN];
+    stream.read_exact(&mut header).map_err(|e| match e.kind() {
+        ErrorKind::UnexpectedEof => anyhow!("the compositor closed the IPC socket"),
+        _ => e.into()
+    })?;
+    let (msg_type, len) = i3_ipc::decode_header(&header).ok_or_else(|| anyhow!("invalid i3 IPC message"))?;
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload)?;
+    Ok((msg_type, serde_json::from_slice(&payload)?))
+}
+
+fn i3_focused_node(node: &Value) -> Option<&Value> {
+    if node["focused"] == Value::Bool(true) {
+        return Some(node);
+    }
+    ["nodes", "floating_nodes"].iter()
+        .flat_map(|key| node[key].as_array().into_iter().flatten())
+        .find_map(i3_focused_node)
+}
+
+// Wayland windows have an app id, X11 ones only a class
+fn i3_app(node: &Value) -> String {
+    node["app_id"].as_str()
+        .or(node["window_properties"]["class"].as_str())
+        .unwrap_or_default()
+        .to_string()
+}
+
+impl I3Provider {
+    fn new(path: &PathBuf) -> Result<I3Provider> {
+        let connect = || UnixStream::connect(path)
+            .map_err(|e| anyhow!("failed to connect to {}: {e}", path.display()));
+        let commands = connect()?;
+        let mut events = connect()?;
+        // the focus can also move off all windows by switching to an empty workspace
+        i3_send(&mut events, SUBSCRIBE, &json!(["window", "workspace"]).to_string())?;
+        let (_, reply) = i3_recv(&mut events)?;
+        if reply["success"] != Value::Bool(true) {
+            return Err(anyhow!("failed to subscribe to i3 events"));
+        }
+        Ok(I3Provider { commands, events, focus: String::new() })
+    }
+    fn query_focus(&mut self) -> Result<String> {
+        i3_send(&mut self.commands, GET_TREE, "")?;
+        let (_, tree) = i3_recv(&mut self.commands)?;
+        Ok(i3_focused_node(&tree).map(i3_app).unwrap_or_default())
+    }
+}
+
+impl ContextProvider for I3Provider {
+    fn current_focus(&mut self) -> Result<String> {
+        self.focus = self.query_focus()?;
+        Ok(self.focus.clone())
+    }
+    fn next_focus(&mut self) -> Result<String> {
+        loop {
+            let (msg_type, _) = i3_recv(&mut self.events)?;
+            if msg_type & EVENT_BIT == 0 {
+                continue;
+            }
+            // the events differ between sway and i3, asking for the tree works with both
+            let focus = self.query_focus()?;
+            if focus != self.focus {
+                self.focus = focus;
+                return Ok(self.focus.clone());
+            }
+        }
+    }
+}
+
+struct HyprlandProvider {
+    dir: PathBuf,
+    events: BufReader<UnixStream>,
+    focus: String,
+}
+
+impl HyprlandProvider {
+    fn new(sig: &str) -> Result<HyprlandProvider> {
+        // older versions keep their sockets in /tmp
+        let runtime_dir = env::var_os("XDG_RUNTIME_DIR").map(|d| PathBuf::from(d).join("hypr").join(sig));
+        let dir = runtime_dir.into_iter()
+            .chain([PathBuf::from("/tmp/hypr").join(sig)])
+            .find(|d| d.join(".socket2.sock").exists())
+            .ok_or_else(|| anyhow!("failed to find the Hyprland sockets for {sig}"))?;
+        HyprlandProvider::in_dir(dir)
+    }
+    fn in_dir(dir: PathBuf) -> Result<HyprlandProvider> {
+        let events = BufReader::new(UnixStream::connect(dir.join(".socket2.sock"))?);
+        Ok(HyprlandProvider { dir, events, focus: String::new() })
+    }
+}
+
+impl ContextProvider for HyprlandProvider {
+    fn current_focus(&mut self) -> Result<String> {
+        // one request per connection, the reply ends with the connection
+        let mut stream = UnixStream::connect(self.dir.join(".socket.sock"))?;
+        stream.write_all(b"j/activewindow")?;
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply)?;
+        let window: Value = serde_json::from_str(&reply)?;
+        self.focus = window["class"].as_str().unwrap_or_default().to_string();
+        Ok(self.focus.clone())
+    }
+    fn next_focus(&mut self) -> Result<String> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.events.read_line(&mut line)? == 0 {
+                return Err(anyhow!("Hyprland closed the event socket"));
+            }
+            // activewindow>>CLASS,TITLE, with both empty if no window is focused
+            let Some(window) = line.trim_end().strip_prefix("activewindow>>") else {
+                continue;
+            };
+            let class = window.split(',').next().unwrap_or_default();
+            if class != self.focus {
+                self.focus = class.to_string();
+                return Ok(self.focus.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::net::UnixListener, thread};
+    use super::*;
+
+    // Reads a request the way sway or i3 would, and checks its type
+    fn expect_request(stream: &mut UnixStream, msg_type: u32) {
+        let mut header = [0u8; HEADER_LEN];
+        stream.read_exact(&mut header).unwrap();
+        let (request_type, len) = i3_ipc::decode_header(&header).unwrap();
+        assert_eq!(request_type, msg_type);
+        let mut payload = vec![0u8; len];
+        stream.read_exact(&mut payload).unwrap();
+    }
+
+    fn tree(focused: Value) -> Value {
+        json!({ "nodes": [
+            { "focused": false, "nodes": [{ "focused": false, "app_id": "other" }] },
+            { "focused": false, "nodes": [], "floating_nodes": [focused] },
+        ] })
+    }
+
+    #[test]
+    fn i3_focus() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sway.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = thread::spawn(move || {
+            let (mut commands, _) = listener.accept().unwrap();
+            let (mut events, _) = listener.accept().unwrap();
+            expect_request(&mut events, SUBSCRIBE);
+            i3_send(&mut events, SUBSCRIBE, &json!({ "success": true }).to_string()).unwrap();
+            let trees = [
+                tree(json!({ "focused": true, "app_id": "foot" })),
+                // X11 windows only have a class
+                tree(json!({ "focused": true, "app_id": null, "window_properties": { "class": "firefox" } })),
+                // events that do not change the focused app are skipped
+                tree(json!({ "focused": true, "app_id": null, "window_properties": { "class": "firefox" } })),
+                tree(json!({ "focused": false, "app_id": "foot" })),
+            ];
+            for (i, tree) in trees.iter().enumerate() {
+                if i > 0 {
+                    i3_send(&mut events, EVENT_BIT | 3, "{}").unwrap();
+                }
+                expect_request(&mut commands, GET_TREE);
+                i3_send(&mut commands, GET_TREE, &tree.to_string()).unwrap();
+            }
+        });
+        let mut provider = I3Provider::new(&path).unwrap();
+        assert_eq!(provider.current_focus().unwrap(), "foot");
+        assert_eq!(provider.next_focus().unwrap(), "firefox");
+        assert_eq!(provider.next_focus().unwrap(), "");
+        server.join().unwrap();
+        // the compositor went away
+        assert!(provider.next_focus().is_err());
+    }
+
+    #[test]
+    fn hyprland_focus() {
+        let dir = tempfile::tempdir().unwrap();
+        let requests = UnixListener::bind(dir.path().join(".socket.sock")).unwrap();
+        let events = UnixListener::bind(dir.path().join(".socket2.sock")).unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = requests.accept().unwrap();
+            let mut request = [0u8; 14];
+            stream.read_exact(&mut request).unwrap();
+            assert_eq!(&request, b"j/activewindow");
+            stream.write_all(json!({ "class": "kitty", "title": "~" }).to_string().as_bytes()).unwrap();
+            drop(stream);
+            let (mut stream, _) = events.accept().unwrap();
+            stream.write_all(b"workspace>>2\nactivewindow>>foot,~\nactivewindow>>foot,vim\nactivewindow>>,\n").unwrap();
+        });
+        let mut provider = HyprlandProvider::in_dir(dir.path().to_path_buf()).unwrap();
+        assert_eq!(provider.current_focus().unwrap(), "kitty");
+        assert_eq!(provider.next_focus().unwrap(), "foot");
+        assert_eq!(provider.next_focus().unwrap(), "");
+        server.join().unwrap();
+        assert!(provider.next_focus().is_err());
+    }
+}
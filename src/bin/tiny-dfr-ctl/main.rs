@@ -0,0 +1,290 @@
+use std::{
+    collections::HashMap,
+    env, fs,
+    io::{BufRead, BufReader, IoSlice, Write},
+    os::{fd::{AsRawFd, RawFd}, linux::net::SocketAddrExt, unix::net::{SocketAddr, UnixStream}},
+    process::exit,
+};
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use nix::{
+    sys::socket::{sendmsg, ControlMessage, MsgFlags},
+    unistd::getuid,
+};
+use serde_json::{json, Value};
+use zbus::{
+    blocking::{fdo::DBusProxy, Connection, MessageIterator},
+    message::Type,
+    zvariant::{OwnedValue, Value as DbusValue},
+    MatchRule,
+};
+
+mod compositor;
+#[path = "../../i3_ipc.rs"]
+mod i3_ipc;
+
+// Must match CONTROL_SOCKET_PATH in the daemon
+const CONTROL_SOCKET_PATH: &str = "/run/tiny-dfr/control.sock";
+// Larger album art is rejected by the daemon
+const MAX_ART_LEN: u64 = 1024 * 1024;
+const PLAYER_PREFIX: &str = "org.mpris.MediaPlayer2.";
+const PLAYER_PATH: &str = "/org/mpris/MediaPlayer2";
+
+const USAGE: &str = "Usage: tiny-dfr-ctl <command>
+
+Commands:
+    status [--json]                 Show the daemon state
+    layer <name>                    Switch to the named layer
+    press <index> [--layer <name>]  Tap a button in the active or the named layer
+    brightness <0-255>              Set the active brightness
+    reload                          Reload the configuration
+    focus <app>                     Tell the daemon which app is focused, \"\" for none
+    follow-focus                    Keep telling the daemon which app is focused in sway, i3 or Hyprland
+    connect-workspaces              Give the daemon a connection to sway or i3 for the workspace widget
+    connect-media                   Give the daemon a connection to the session bus for the media widget,
+                                    and keep sending it the album art of the players";
+
+fn parse_args(args: &[String]) -> Option<(Value, bool)> {
+    let args = args.iter().map(|a| a.as_str()).collect::<Vec<_>>();
+    let request = match args.as_slice() {
+        ["status"] => (json!({ "command": "status" }), false),
+        ["status", "--json"] => (json!({ "command": "status" }), true),
+        ["layer", name] => (json!({ "command": "layer", "name": name }), false),
+        ["press", index] => (json!({ "command": "press", "button": index.parse::<usize>().ok()? }), false),
+        ["press", index, "--layer", name] => {
+            (json!({ "command": "press", "button": index.parse::<usize>().ok()?, "layer": name }), false)
+        },
+        ["brightness", value] => (json!({ "command": "brightness", "value": value.parse::<u32>().ok()? }), false),
+        ["reload"] => (json!({ "command": "reload" }), false),
+        ["focus", app] => (json!({ "command": "focus", "app": app }), false),
+        _ => return None
+    };
+    Some(request)
+}
+
+fn send(request: &Value) -> Result<Value> {
+    send_with_fd(request, None)
+}
+
+// The fd, if any, is passed along with the first byte of the request
+fn send_with_fd(request: &Value, fd: Option<RawFd>) -> Result<Value> {
+    let mut stream = UnixStream::connect(CONTROL_SOCKET_PATH)
+        .map_err(|e| anyhow!("failed to connect to {CONTROL_SOCKET_PATH}: {e}"))?;
+    let mut line = request.to_string();
+    line.push('\n');
+    let mut sent = 0;
+    if let Some(fd) = fd {
+        let fds = [fd];
+        sent = sendmsg::<()>(stream.as_raw_fd(), &[IoSlice::new(line.as_bytes())], &[ControlMessage::ScmRights(&fds)], MsgFlags::empty(), None)?;
+    }
+    stream.write_all(&line.as_bytes()[sent..])?;
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply)?;
+    let reply: Value = serde_json::from_str(&reply)?;
+    if reply["ok"] != Value::Bool(true) {
+        return Err(anyhow!("{}", reply["error"].as_str().unwrap_or("unknown error")));
+    }
+    Ok(reply)
+}
+
+fn print_status(status: &Value) {
+    println!("Active layer: {}", status["active_layer"].as_str().unwrap_or_default());
+    println!("Fn-lock: {}", if status["fn_locked"] == Value::Bool(true) { "on" } else { "off" });
+    println!("Backlight: {}", status["backlight"]);
+    println!("Active brightness: {}", status["active_brightness"]);
+    println!("Focused app: {}", status["focused_app"].as_str().unwrap_or_default());
+    println!("Layers:");
+    for layer in status["layers"].as_array().into_iter().flatten() {
+        let buttons = layer["buttons"].as_array().into_iter().flatten()
+            .map(|b| b.as_str().unwrap_or_default())
+            .collect::<Vec<_>>();
+        println!("    {}: {}", layer["name"].as_str().unwrap_or_default(), buttons.join(" "));
+    }
+}
+
+// Runs in the user session, where the compositor socket can be reached
+fn follow_focus() -> Result<()> {
+    let mut provider = compositor::from_env()?;
+    let mut app = provider.current_focus()?;
+    loop {
+        // the daemon may be restarting, the next focus change will catch it up
+        if let Err(err) = send(&json!({ "command": "focus", "app": app })) {
+            eprintln!("tiny-dfr-ctl: {err}");
+        }
+        app = provider.next_focus()?;
+    }
+}
+
+// The daemon cannot reach the compositor socket, so it is handed a connection
+// to it, which it keeps until the compositor exits
+fn connect_workspaces() -> Result<()> {
+    let path = compositor::i3_socket_path().ok_or_else(|| anyhow!("no sway or i3 socket found, SWAYSOCK and I3SOCK are unset"))?;
+    let stream = UnixStream::connect(&path)
+        .map_err(|e| anyhow!("failed to connect to {}: {e}", path.display()))?;
+    send_with_fd(&json!({ "command": "workspace_socket" }), Some(stream.as_raw_fd()))?;
+    Ok(())
+}
+
+// Only unix socket addresses are supported, which is what session buses use
+fn connect_session_bus() -> Result<UnixStream> {
+    let address = env::var("DBUS_SESSION_BUS_ADDRESS").map_err(|_| anyhow!("DBUS_SESSION_BUS_ADDRESS is unset"))?;
+    for entry in address.split(';') {
+        let Some(params) = entry.strip_prefix("unix:") else {
+            continue;
+        };
+        for param in params.split(',') {
+            let addr = match param.split_once('=') {
+                Some(("path", path)) => SocketAddr::from_pathname(path)?,
+                Some(("abstract", name)) => SocketAddr::from_abstract_name(name)?,
+                _ => continue
+            };
+            if let Ok(stream) = UnixStream::connect_addr(&addr) {
+                return Ok(stream);
+            }
+        }
+    }
+    Err(anyhow!("failed to connect to the session bus at {address}"))
+}
+
+// Like for the workspaces, the daemon authenticates on the connection as
+// the user that opened it
+fn connect_media() -> Result<()> {
+    let stream = connect_session_bus()?;
+    send_with_fd(&json!({ "command": "media_bus", "uid": getuid().as_raw() }), Some(stream.as_raw_fd()))?;
+    follow_art()
+}
+
+fn percent_decode(s: &str) -> String {
+    let mut bytes = Vec::new();
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        let hex = tail.get(..2).and_then(|h| std::str::from_utf8(h).ok()).and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (b, hex) {
+            (b'%', Some(byte)) => {
+                bytes.push(byte);
+                rest = &tail[2..];
+            },
+            _ => {
+                bytes.push(b);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+fn art_url(conn: &Connection, player: &str) -> Result<Option<String>> {
+    let reply = conn.call_method(Some(player), PLAYER_PATH, Some("org.freedesktop.DBus.Properties"), "GetAll", &("org.mpris.MediaPlayer2.Player"))?;
+    let props: HashMap<String, OwnedValue> = reply.body().deserialize()?;
+    let Some(metadata) = props.get("Metadata") else {
+        return Ok(None);
+    };
+    let metadata: HashMap<String, OwnedValue> = metadata.try_clone()?.try_into()?;
+    Ok(match metadata.get("mpris:artUrl").map(|url| &**url) {
+        Some(DbusValue::Str(url)) if !url.is_empty() => Some(url.to_string()),
+        _ => None
+    })
+}
+
+// Players give their album art as a url, only local files are supported
+fn send_art(url: &str) -> Result<()> {
+    let path = percent_decode(url.strip_prefix("file://").ok_or_else(|| anyhow!("unsupported art url {url}"))?);
+    if fs::metadata(&path)?.len() > MAX_ART_LEN {
+        return Err(anyhow!("{path} is larger than {MAX_ART_LEN} bytes"));
+    }
+    send(&json!({ "command": "media_art", "url": url, "data": BASE64.encode(fs::read(&path)?) }))?;
+    Ok(())
+}
+
+// The daemon cannot read files in the user session, so the album art of the
+// players is read here and sent to it whenever one of them shows another one
+fn follow_art() -> Result<()> {
+    let conn = Connection::session()?;
+    let dbus = DBusProxy::new(&conn)?;
+    dbus.add_match_rule(MatchRule::builder()
+        .msg_type(Type::Signal)
+        .interface("org.freedesktop.DBus.Properties")?
+        .member("PropertiesChanged")?
+        .path(PLAYER_PATH)?
+        .build())?;
+    dbus.add_match_rule(MatchRule::builder()
+        .msg_type(Type::Signal)
+        .interface("org.freedesktop.DBus")?
+        .member("NameOwnerChanged")?
+        .arg0ns("org.mpris.MediaPlayer2")?
+        .build())?;
+    let messages = MessageIterator::from(&conn);
+    // the url of the art last sent for each player
+    let mut sent: HashMap<String, String> = HashMap::new();
+    let mut send_new_art = || -> Result<()> {
+        let players = dbus.list_names()?.into_iter()
+            .map(|name| name.to_string())
+            .filter(|name| name.starts_with(PLAYER_PREFIX))
+            .collect::<Vec<_>>();
+        sent.retain(|player, _| players.contains(player));
+        for player in players {
+            // a player that does not answer is skipped until the next change
+            let Ok(Some(url)) = art_url(&conn, &player) else {
+                continue;
+            };
+            if sent.get(&player) == Some(&url) {
+                continue;
+            }
+            if let Err(err) = send_art(&url) {
+                eprintln!("tiny-dfr-ctl: failed to send album art: {err}");
+            }
+            sent.insert(player, url);
+        }
+        Ok(())
+    };
+    send_new_art()?;
+    for msg in messages {
+        if msg?.header().message_type() == Type::Signal {
+            send_new_art()?;
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if args == ["connect-media"] {
+        if let Err(err) = connect_media() {
+            eprintln!("tiny-dfr-ctl: {err}");
+            exit(1);
+        }
+        return;
+    }
+    if args == ["connect-workspaces"] {
+        if let Err(err) = connect_workspaces() {
+            eprintln!("tiny-dfr-ctl: {err}");
+            exit(1);
+        }
+        return;
+    }
+    if args == ["follow-focus"] {
+        if let Err(err) = follow_focus() {
+            eprintln!("tiny-dfr-ctl: {err}");
+            exit(1);
+        }
+        return;
+    }
+    let Some((request, json_output)) = parse_args(&args) else {
+        eprintln!("{USAGE}");
+        exit(2);
+    };
+    let reply = match send(&request) {
+        Ok(reply) => reply,
+        Err(err) => {
+            eprintln!("tiny-dfr-ctl: {err}");
+            exit(1);
+        }
+    };
+    if request["command"] == "status" {
+        if json_output {
+            println!("{}", reply["status"]);
+        } else {
+            print_status(&reply["status"]);
+        }
+    }
+}
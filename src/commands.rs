@@ -0,0 +1,347 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    ffi::CString,
+    fs::File,
+    io::{self, Read, Write},
+    os::{
+        fd::{AsFd, AsRawFd, FromRawFd, OwnedFd},
+        unix::{net::UnixStream, process::CommandExt},
+    },
+    path::Path,
+    process::{Child, Command, Stdio},
+    time::{Duration, Instant},
+};
+use anyhow::Result;
+use nix::{
+    errno::Errno,
+    sys::{
+        epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags},
+        signal::{killpg, Signal},
+        socket::{recv, MsgFlags},
+    },
+    unistd::{getuid, initgroups, setgid, setuid, Pid, User},
+};
+use serde::{Deserialize, Serialize};
+
+pub const COMMAND_RUNNER_ARG: &str = "--command-runner";
+const STDIN_TOKEN: u64 = u64::MAX;
+const PATH: &str = "/usr/local/bin:/usr/bin:/bin";
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct CommandConfig {
+    pub run: String,
+    // in milliseconds, the command is killed if it runs for longer
+    pub timeout: Option<u64>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum CommandSpec {
+    Short(String),
+    Full(CommandConfig)
+}
+
+// What the daemon sends the runner, which writes the id back once the command exited
+#[derive(Deserialize, Serialize)]
+struct RunRequest {
+    id: u64,
+    #[serde(flatten)]
+    cmd: CommandConfig,
+}
+
+impl From<CommandSpec> for CommandConfig {
+    fn from(spec: CommandSpec) -> CommandConfig {
+        match spec {
+            CommandSpec::Short(run) => CommandConfig { run, timeout: None, env: BTreeMap::new() },
+            CommandSpec::Full(cfg) => cfg
+        }
+    }
+}
+
+fn pidfd_open(pid: u32) -> io::Result<OwnedFd> {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) })
+}
+
+// The daemon drops its privileges shortly after starting, so it can no
+// longer switch to the user commands should run as. Instead, a copy of the
+// daemon is started with root privileges beforehand, which switches to that
+// user, except where systemd runs, see runner_main, then receives commands
+// over a socket, and spawns and reaps them on the daemon's behalf.
+pub struct CommandRunner {
+    stream: UnixStream,
+    runner: Child,
+    pidfd: Option<OwnedFd>,
+    next_id: u64,
+    // sent to the runner and not reported as exited yet
+    running: HashSet<u64>,
+    buf: Vec<u8>,
+}
+
+impl CommandRunner {
+    pub fn new(user: &str) -> CommandRunner {
+        let (stream, runner_stream) = UnixStream::pair().unwrap();
+        let runner = Command::new("/proc/self/exe")
+            .arg(COMMAND_RUNNER_ARG)
+            .arg(user)
+            .stdin(Stdio::from(OwnedFd::from(runner_stream)))
+            .spawn()
+            .unwrap();
+        let pidfd = Some(pidfd_open(runner.id()).unwrap());
+        CommandRunner { stream, runner, pidfd, next_id: 0, running: HashSet::new(), buf: Vec::new() }
+    }
+    // Returns an id to check whether the command is still running with, if it was sent
+    pub fn run(&mut self, cmd: &CommandConfig) -> Option<u64> {
+        if self.pidfd.is_none() {
+            println!("Command runner is not running, ignoring command {}", cmd.run);
+            return None;
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        let mut line = serde_json::to_string(&RunRequest { id, cmd: cmd.clone() }).unwrap();
+        line.push('\n');
+        if let Err(err) = self.stream.write_all(line.as_bytes()) {
+            println!("Failed to send command {} to the runner: {err}", cmd.run);
+            return None;
+        }
+        self.running.insert(id);
+        Some(id)
+    }
+    pub fn is_running(&self, id: u64) -> bool {
+        self.running.contains(&id)
+    }
+    // Should be called whenever the runner's pidfd or stream becomes readable
+    pub fn update(&mut self, epoll: &Epoll) {
+        if self.pidfd.is_none() {
+            return;
+        }
+        let mut chunk = [0u8; 1024];
+        while let Ok(len @ 1..) = recv(self.stream.as_raw_fd(), &mut chunk, MsgFlags::MSG_DONTWAIT) {
+            self.buf.extend_from_slice(&chunk[..len]);
+        }
+        while let Some(pos) = self.buf.iter().position(|b| *b == b'\n') {
+            let line = self.buf.drain(..=pos).collect::<Vec<_>>();
+            if let Some(id) = std::str::from_utf8(&line).ok().and_then(|id| id.trim().parse().ok()) {
+                self.running.remove(&id);
+            }
+        }
+        let exited = match self.runner.try_wait() {
+            Ok(None) => return,
+            Ok(Some(status)) => format!("exited with {status}"),
+            Err(err) => format!("could not be waited for: {err}")
+        };
+        println!("Command runner {exited}, commands will no longer be run");
+        // closing the pidfd also removes it from the epoll set, the stream stays open
+        self.pidfd = None;
+        if let Err(err) = epoll.delete(self.stream.as_fd()) {
+            println!("Failed to stop watching the command runner: {err}");
+        }
+        self.running.clear();
+    }
+    pub fn fd(&self) -> &impl AsFd {
+        self.pidfd.as_ref().unwrap()
+    }
+    // readable when the runner reports commands that exited
+    pub fn stream_fd(&self) -> &impl AsFd {
+        &self.stream
+    }
+}
+
+struct RunningCommand {
+    id: u64,
+    child: Child,
+    pidfd: OwnedFd,
+    run: String,
+    deadline: Option<Instant>,
+}
+
+// The daemon's service is sandboxed, with the home directories and the user
+// session hidden and the filesystem read-only, and its children would be too.
+// Where systemd runs, commands are started as transient services of their own
+// instead, which it also stops once they time out.
+fn systemd_running() -> bool {
+    Path::new("/run/systemd/system").exists()
+}
+
+// Returns the child, and when it should be killed if it is still running
+fn spawn_command(user: &User, cmd: &CommandConfig, systemd: bool) -> Result<(Child, Option<Instant>)> {
+    let mut env = vec![
+        ("PATH".to_string(), PATH.to_string()),
+        ("HOME".to_string(), user.dir.to_string_lossy().into_owned()),
+        ("USER".to_string(), user.name.clone()),
+    ];
+    env.extend(cmd.env.iter().map(|(k, v)| (k.clone(), v.clone())));
+    if systemd {
+        let mut command = Command::new("systemd-run");
+        command
+            .args(["--quiet", "--wait", "--collect", "--service-type=exec"])
+            .arg(format!("--uid={}", user.uid))
+            .arg(format!("--gid={}", user.gid))
+            .arg(format!("--description=tiny-dfr command {}", cmd.run));
+        if let Some(timeout) = cmd.timeout {
+            command.arg(format!("--property=RuntimeMaxSec={timeout}ms"));
+        }
+        for (k, v) in &env {
+            command.arg(format!("--setenv={k}={v}"));
+        }
+        let child = command
+            .args(["--", "/bin/sh", "-c", &cmd.run])
+            .env_clear()
+            .env("PATH", PATH)
+            .stdin(Stdio::null())
+            .spawn()?;
+        return Ok((child, None));
+    }
+    let child = Command::new("/bin/sh")
+        .arg("-c")
+        .arg(&cmd.run)
+        .env_clear()
+        .envs(env)
+        .current_dir("/")
+        .stdin(Stdio::null())
+        // so that a timeout kills everything the command started
+        .process_group(0)
+        .spawn()?;
+    Ok((child, cmd.timeout.map(|ms| Instant::now() + Duration::from_millis(ms))))
+}
+
+fn switch_user(user: &User) -> nix::Result<()> {
+    if getuid() == user.uid {
+        return Ok(());
+    }
+    initgroups(&CString::new(user.name.as_str()).unwrap(), user.gid)?;
+    setgid(user.gid)?;
+    setuid(user.uid)
+}
+
+// Wakes the runner once the child exits
+fn watch(epoll: &Epoll, child: &Child, token: u64) -> io::Result<OwnedFd> {
+    let pidfd = pidfd_open(child.id())?;
+    epoll.add(pidfd.as_fd(), EpollEvent::new(EpollFlags::EPOLLIN, token))?;
+    Ok(pidfd)
+}
+
+pub fn runner_main(user_name: &str) {
+    let user = match User::from_name(user_name).unwrap() {
+        Some(user) => user,
+        None => panic!("Invalid configuration, user {user_name} does not exist")
+    };
+    // Only root may have systemd start a transient system service as another user,
+    // so where systemd runs the runner stays root, and only ever runs systemd-run
+    // with the uid of the user. Otherwise it runs the commands itself, as the user.
+    let systemd = systemd_running();
+    if !systemd {
+        if let Err(err) = switch_user(&user) {
+            println!("Failed to switch to user {user_name}, commands will not be run: {err}");
+            return;
+        }
+    }
+    let mut input = File::from(io::stdin().as_fd().try_clone_to_owned().unwrap());
+    let epoll = Epoll::new(EpollCreateFlags::empty()).unwrap();
+    epoll.add(input.as_fd(), EpollEvent::new(EpollFlags::EPOLLIN, STDIN_TOKEN)).unwrap();
+
+    let mut buf = Vec::new();
+    let mut running: HashMap<u64, RunningCommand> = HashMap::new();
+    let mut next_id = 0;
+    loop {
+        let now = Instant::now();
+        let timeout = running.values()
+            .filter_map(|cmd| cmd.deadline)
+            .min()
+            .map(|deadline| deadline.saturating_duration_since(now).as_millis().min(u16::MAX as u128) as u16 + 1);
+        let mut events = [EpollEvent::empty(); 8];
+        let n = match epoll.wait(&mut events, timeout) {
+            Ok(n) => n,
+            Err(Errno::EINTR) => 0,
+            Err(err) => {
+                println!("Command runner failed to wait for events: {err}");
+                0
+            }
+        };
+        for event in &events[..n] {
+            if event.data() != STDIN_TOKEN {
+                continue;
+            }
+            let mut chunk = [0u8; 4096];
+            let len = match input.read(&mut chunk) {
+                Ok(len) => len,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    println!("Command runner failed to read from the daemon: {err}");
+                    0
+                }
+            };
+            if len == 0 {
+                // the daemon went away
+                return;
+            }
+            buf.extend_from_slice(&chunk[..len]);
+            while let Some(pos) = buf.iter().position(|b| *b == b'\n') {
+                let line = buf.drain(..=pos).collect::<Vec<_>>();
+                let RunRequest { id, cmd } = match serde_json::from_slice(&line) {
+                    Ok(request) => request,
+                    Err(err) => {
+                        println!("Invalid command request: {err}");
+                        continue;
+                    }
+                };
+                let (mut child, deadline) = match spawn_command(&user, &cmd, systemd) {
+                    Ok(spawned) => spawned,
+                    Err(err) => {
+                        println!("Failed to run command {}: {err}", cmd.run);
+                        _ = writeln!(input, "{id}");
+                        continue;
+                    }
+                };
+                let pidfd = match watch(&epoll, &child, next_id) {
+                    Ok(pidfd) => pidfd,
+                    Err(err) => {
+                        // it would never be reaped or timed out otherwise
+                        println!("Failed to watch command {}, killing it: {err}", cmd.run);
+                        _ = child.kill();
+                        _ = child.wait();
+                        _ = writeln!(input, "{id}");
+                        continue;
+                    }
+                };
+                running.insert(next_id, RunningCommand { id, child, pidfd, deadline, run: cmd.run });
+                next_id += 1;
+            }
+        }
+        let now = Instant::now();
+        running.retain(|_, cmd| {
+            let exited = match cmd.child.try_wait() {
+                Ok(None) => false,
+                Ok(Some(status)) => {
+                    if !status.success() {
+                        println!("Command {} exited with {status}", cmd.run);
+                    }
+                    true
+                },
+                Err(err) => {
+                    println!("Failed to wait for command {}, forgetting it: {err}", cmd.run);
+                    true
+                }
+            };
+            if exited {
+                if let Err(err) = epoll.delete(cmd.pidfd.as_fd()) {
+                    println!("Failed to stop watching command {}: {err}", cmd.run);
+                }
+                _ = writeln!(input, "{}", cmd.id);
+                return false;
+            }
+            if cmd.deadline.is_some_and(|deadline| deadline <= now) {
+                println!("Command {} timed out, killing it", cmd.run);
+                _ = killpg(Pid::from_raw(cmd.child.id() as i32), Signal::SIGKILL);
+                cmd.deadline = None;
+            }
+            true
+        });
+    }
+}
@@ -1,11 +1,20 @@
 use std::{
+    collections::BTreeMap,
+    env,
     fs::read_to_string,
-    os::fd::AsFd
+    os::fd::AsFd,
+    path::{Path, PathBuf},
 };
-use anyhow::Error;
 use cairo::FontFace;
-use crate::FunctionLayer;
+use crate::{ButtonAction, FunctionLayer};
 use crate::fonts::{FontConfig, Pattern};
+use crate::layers::LayerAction;
+use crate::macros::{KeyChord, MacroStep, RepeatConfig};
+use crate::media::MediaCommand;
+use crate::commands::CommandSpec;
+use crate::widgets::WidgetKind;
+use crate::slider::SliderConfig;
+use crate::modifiers::Modifier;
 use freetype::Library as FtLibrary;
 use input_linux::Key;
 use nix::{
@@ -15,6 +24,17 @@ use nix::{
 use serde::Deserialize;
 
 const USER_CFG_PATH: &'static str = "/etc/tiny-dfr/config.toml";
+const BASE_CFG_PATH: &str = "/usr/share/tiny-dfr/config.toml";
+// Overrides the root the daemon's own files are found under, so that it can be run by a test
+const ROOT_ENV: &str = "TINY_DFR_ROOT";
+
+// Where one of the daemon's own files or directories is
+pub fn system_path(path: &str) -> PathBuf {
+    match env::var_os(ROOT_ENV) {
+        Some(root) => Path::new(&root).join(path.trim_start_matches('/')),
+        None => PathBuf::from(path)
+    }
+}
 
 pub struct Config {
     pub show_button_outlines: bool,
@@ -22,6 +42,105 @@ pub struct Config {
     pub font_face: FontFace,
     pub adaptive_brightness: bool,
     pub active_brightness: u32,
+    pub default_layer: usize,
+    pub fn_layer: Option<usize>,
+    pub modifier_layers: Vec<(Key, usize)>,
+    pub fn_lock_double_tap: bool,
+    pub fn_lock_chord: Vec<Key>,
+    pub command_user: String,
+    pub control_socket_group: String,
+    // applied when the bar is swiped on buttons that have no swipe actions of their own
+    pub swipe_left: Option<LayerAction>,
+    pub swipe_right: Option<LayerAction>,
+    // app ids, lowercased, and the layer shown instead of DefaultLayer while they are focused
+    pub app_layers: Vec<(String, usize)>,
+    pub theme: Theme,
+}
+
+pub struct Theme {
+    pub background: Color,
+    pub foreground: Color,
+    pub button_inactive: Color,
+    pub button_active: Color,
+    pub button_toggled: Color,
+    pub slider_fill: Color,
+    pub button_spacing: f64,
+    pub button_radius: f64,
+    // the part of the height of the bar left free above and below the buttons,
+    // which their rounded corners reach into
+    pub button_inset: f64,
+    pub font_size: f64,
+    pub icon_size: f64,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "PascalCase")]
+struct ThemeProxy {
+    name: Option<String>,
+    background: Option<Color>,
+    foreground: Option<Color>,
+    button_inactive: Option<Color>,
+    button_active: Option<Color>,
+    button_toggled: Option<Color>,
+    slider_fill: Option<Color>,
+    button_spacing: Option<f64>,
+    button_radius: Option<f64>,
+    button_inset: Option<f64>,
+    font_size: Option<f64>,
+    icon_size: Option<f64>,
+}
+
+impl ThemeProxy {
+    // The keys that are set, falling back to those of base
+    fn over(self, base: ThemeProxy) -> ThemeProxy {
+        ThemeProxy {
+            name: self.name.or(base.name),
+            background: self.background.or(base.background),
+            foreground: self.foreground.or(base.foreground),
+            button_inactive: self.button_inactive.or(base.button_inactive),
+            button_active: self.button_active.or(base.button_active),
+            button_toggled: self.button_toggled.or(base.button_toggled),
+            slider_fill: self.slider_fill.or(base.slider_fill),
+            button_spacing: self.button_spacing.or(base.button_spacing),
+            button_radius: self.button_radius.or(base.button_radius),
+            button_inset: self.button_inset.or(base.button_inset),
+            font_size: self.font_size.or(base.font_size),
+            icon_size: self.icon_size.or(base.icon_size),
+        }
+    }
+}
+
+// Named themes have the keys of the Theme section, without the section header
+fn load_theme(name: &str) -> ThemeProxy {
+    for dir in ["/etc/tiny-dfr/themes", "/usr/share/tiny-dfr/themes"] {
+        if let Ok(text) = read_to_string(system_path(dir).join(format!("{name}.toml"))) {
+            return toml::from_str(&text).unwrap_or_else(|e| panic!("Invalid theme {name}: {e}"));
+        }
+    }
+    panic!("Invalid configuration, theme {name} does not exist")
+}
+
+fn build_theme(theme: ThemeProxy) -> Theme {
+    let theme = Theme {
+        background: theme.background.unwrap(),
+        foreground: theme.foreground.unwrap(),
+        button_inactive: theme.button_inactive.unwrap(),
+        button_active: theme.button_active.unwrap(),
+        button_toggled: theme.button_toggled.unwrap(),
+        slider_fill: theme.slider_fill.unwrap(),
+        button_spacing: theme.button_spacing.unwrap(),
+        button_radius: theme.button_radius.unwrap(),
+        button_inset: theme.button_inset.unwrap(),
+        font_size: theme.font_size.unwrap(),
+        icon_size: theme.icon_size.unwrap(),
+    };
+    if !(0.0..0.5).contains(&theme.button_inset) {
+        panic!("Invalid configuration, ButtonInset must be at least 0 and less than 0.5");
+    }
+    if theme.button_spacing < 0.0 || theme.button_radius < 0.0 || theme.font_size <= 0.0 || theme.icon_size <= 0.0 {
+        panic!("Invalid configuration, ButtonSpacing and ButtonRadius must not be negative, and FontSize and IconSize must be positive");
+    }
+    theme
 }
 
 #[derive(Deserialize)]
@@ -33,21 +152,140 @@ struct ConfigProxy {
     font_template: Option<String>,
     adaptive_brightness: Option<bool>,
     active_brightness: Option<u32>,
+    default_layer: Option<String>,
+    fn_layer: Option<String>,
+    modifier_layers: Option<BTreeMap<Key, String>>,
+    fn_lock_double_tap: Option<bool>,
+    fn_lock_chord: Option<Vec<Key>>,
+    command_user: Option<String>,
+    control_socket_group: Option<String>,
+    swipe_left: Option<LayerAction>,
+    swipe_right: Option<LayerAction>,
+    app_layers: Option<BTreeMap<String, String>>,
+    theme: Option<ThemeProxy>,
+    layers: Option<BTreeMap<String, Vec<ButtonConfig>>>,
     primary_layer_keys: Option<Vec<ButtonConfig>>,
     media_layer_keys: Option<Vec<ButtonConfig>>
 }
 
+impl ConfigProxy {
+    // Translate the pre-named-layers keys into their named equivalents,
+    // so that old configs keep working.
+    fn migrate_legacy(&mut self) {
+        if let Some(media_default) = self.media_layer_default.take() {
+            let (default, fn_layer) = if media_default { ("media", "fkeys") } else { ("fkeys", "media") };
+            self.default_layer = self.default_layer.take().or(Some(default.into()));
+            self.fn_layer = self.fn_layer.take().or(Some(fn_layer.into()));
+        }
+        let layers = self.layers.get_or_insert_with(BTreeMap::new);
+        if let Some(keys) = self.primary_layer_keys.take() {
+            layers.entry("fkeys".into()).or_insert(keys);
+        }
+        if let Some(keys) = self.media_layer_keys.take() {
+            layers.entry("media".into()).or_insert(keys);
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "PascalCase")]
+pub struct ActionConfig {
+    pub action: Option<KeyChord>,
+    #[serde(rename = "Macro")]
+    pub macro_steps: Option<Vec<MacroStep>>,
+    pub layer: Option<LayerAction>,
+    pub command: Option<CommandSpec>,
+    pub media: Option<MediaCommand>,
+}
+
+// How a button looks and what it does while toggled on, or while modifiers
+// are held. Fields that are not set fall back to the button's own.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "PascalCase")]
+pub struct StateConfig {
+    #[serde(alias = "Svg")]
+    pub icon: Option<String>,
+    pub text: Option<String>,
+    pub theme: Option<String>,
+    #[serde(flatten)]
+    pub action: ActionConfig,
+}
+
 #[derive(Deserialize)]
 #[serde(rename_all = "PascalCase")]
+pub struct VariantConfig {
+    pub modifiers: Vec<Modifier>,
+    #[serde(flatten)]
+    pub state: StateConfig,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "PascalCase")]
 pub struct ButtonConfig {
     #[serde(alias = "Svg")]
     pub icon: Option<String>,
     pub text: Option<String>,
     pub theme: Option<String>,
-    pub action: Key,
+    pub action: Option<KeyChord>,
+    #[serde(rename = "Macro")]
+    pub macro_steps: Option<Vec<MacroStep>>,
+    pub layer: Option<LayerAction>,
+    pub command: Option<CommandSpec>,
+    pub media: Option<MediaCommand>,
+    pub repeat: Option<RepeatConfig>,
+    pub slider: Option<SliderConfig>,
+    pub widget: Option<WidgetKind>,
+    pub format: Option<String>,
+    // for the workspace widget, counting from 1
+    pub workspace: Option<usize>,
+    pub on_hold: Option<Box<ButtonConfig>>,
+    // in milliseconds
+    pub hold_time: Option<u64>,
+    pub toggle: Option<bool>,
+    pub on: Option<StateConfig>,
+    pub variants: Option<Vec<VariantConfig>>,
+    pub double_tap: Option<ActionConfig>,
+    pub long_press: Option<ActionConfig>,
+    pub swipe_left: Option<ActionConfig>,
+    pub swipe_right: Option<ActionConfig>,
     pub stretch: Option<usize>,
 }
 
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Color {
+    pub r: f64,
+    pub g: f64,
+    pub b: f64,
+    pub a: f64,
+}
+
+impl Color {
+    // Accepts #RRGGBB and #RRGGBBAA
+    pub fn parse(s: &str) -> Result<Color, String> {
+        let hex = s.strip_prefix('#').filter(|h| (h.len() == 6 || h.len() == 8) && h.is_ascii())
+            .ok_or_else(|| format!("invalid color {s}, expected #RRGGBB or #RRGGBBAA"))?;
+        let channel = |i: usize| {
+            u8::from_str_radix(&hex[i..i + 2], 16)
+                .map(|v| v as f64 / 255.0)
+                .map_err(|_| format!("invalid color {s}, expected #RRGGBB or #RRGGBBAA"))
+        };
+        Ok(Color {
+            r: channel(0)?,
+            g: channel(2)?,
+            b: channel(4)?,
+            a: if hex.len() == 8 { channel(6)? } else { 1.0 },
+        })
+    }
+}
+
+impl TryFrom<String> for Color {
+    type Error = String;
+    fn try_from(s: String) -> Result<Color, String> {
+        Color::parse(&s)
+    }
+}
+
 fn load_font(name: &str) -> FontFace {
     let fontconfig = FontConfig::new();
     let mut pattern = Pattern::new(name);
@@ -63,48 +301,112 @@ fn load_font(name: &str) -> FontFace {
     FontFace::create_from_ft(&face).unwrap()
 }
 
-fn load_config(width: u16) -> (Config, [FunctionLayer; 2]) {
-    let mut base = toml::from_str::<ConfigProxy>(&read_to_string("/usr/share/tiny-dfr/config.toml").unwrap()).unwrap();
-    let user = read_to_string(USER_CFG_PATH).map_err::<Error, _>(|e| e.into())
-        .and_then(|r| Ok(toml::from_str::<ConfigProxy>(&r)?));
-    if let Ok(user) = user {
-        base.media_layer_default = user.media_layer_default.or(base.media_layer_default);
+fn find_layer(layers: &[FunctionLayer], name: &str) -> usize {
+    match layers.iter().position(|l| l.name == name) {
+        Some(idx) => idx,
+        None => panic!("Invalid configuration, layer {name} is not defined")
+    }
+}
+
+fn load_config(width: u16) -> (Config, Vec<FunctionLayer>) {
+    let user = read_to_string(system_path(USER_CFG_PATH)).ok();
+    parse_config(&read_to_string(system_path(BASE_CFG_PATH)).unwrap(), user.as_deref(), width)
+}
+
+// The user config is ignored if it cannot be parsed
+pub fn parse_config(base: &str, user: Option<&str>, width: u16) -> (Config, Vec<FunctionLayer>) {
+    let mut base = toml::from_str::<ConfigProxy>(base).unwrap();
+    base.migrate_legacy();
+    let mut user_theme = ThemeProxy::default();
+    if let Some(mut user) = user.and_then(|r| toml::from_str::<ConfigProxy>(r).ok()) {
+        user.migrate_legacy();
+        user_theme = user.theme.unwrap_or_default();
         base.show_button_outlines = user.show_button_outlines.or(base.show_button_outlines);
         base.enable_pixel_shift = user.enable_pixel_shift.or(base.enable_pixel_shift);
         base.font_template = user.font_template.or(base.font_template);
         base.adaptive_brightness = user.adaptive_brightness.or(base.adaptive_brightness);
-        base.media_layer_keys = user.media_layer_keys.or(base.media_layer_keys);
-        base.primary_layer_keys = user.primary_layer_keys.or(base.primary_layer_keys);
+        base.default_layer = user.default_layer.or(base.default_layer);
+        base.fn_layer = user.fn_layer.or(base.fn_layer);
+        base.modifier_layers = user.modifier_layers.or(base.modifier_layers);
+        base.fn_lock_double_tap = user.fn_lock_double_tap.or(base.fn_lock_double_tap);
+        base.fn_lock_chord = user.fn_lock_chord.or(base.fn_lock_chord);
+        base.command_user = user.command_user.or(base.command_user);
+        base.control_socket_group = user.control_socket_group.or(base.control_socket_group);
         base.active_brightness = user.active_brightness.or(base.active_brightness);
+        base.swipe_left = user.swipe_left.or(base.swipe_left);
+        base.swipe_right = user.swipe_right.or(base.swipe_right);
+        base.app_layers = user.app_layers.or(base.app_layers);
+        if let (Some(layers), Some(user_layers)) = (base.layers.as_mut(), user.layers) {
+            layers.extend(user_layers);
+        }
     };
-    let mut media_layer_keys = base.media_layer_keys.unwrap();
-    let mut primary_layer_keys = base.primary_layer_keys.unwrap();
-    if width >= 2170 {
-        for layer in [&mut media_layer_keys, &mut primary_layer_keys] {
-            layer.insert(0, ButtonConfig { icon: None, text: Some("esc".into()), theme: None, action: Key::Esc, stretch: None });
+    let layers = base.layers.unwrap().into_iter().map(|(name, mut keys)| {
+        if width >= 2170 {
+            keys.insert(0, ButtonConfig { text: Some("esc".into()), action: Some(KeyChord::Single(Key::Esc)), ..Default::default() });
+        }
+        FunctionLayer::with_config(name, keys)
+    }).collect::<Vec<_>>();
+    let default_layer = find_layer(&layers, &base.default_layer.unwrap());
+    let fn_layer = base.fn_layer.map(|name| find_layer(&layers, &name));
+    let modifier_layers = base.modifier_layers.unwrap_or_default().into_iter()
+        .map(|(key, name)| (key, find_layer(&layers, &name)))
+        .collect();
+    let app_layers = base.app_layers.unwrap_or_default().into_iter()
+        .map(|(app, name)| (app.to_lowercase(), find_layer(&layers, &name)))
+        .collect();
+    for layer in &layers {
+        for (_, button) in &layer.buttons {
+            for action in button.actions() {
+                if let ButtonAction::Layer(action) = action {
+                    if let Some(name) = action.target() {
+                        find_layer(&layers, name);
+                    }
+                }
+            }
         }
     }
-    let media_layer = FunctionLayer::with_config(media_layer_keys);
-    let fkey_layer = FunctionLayer::with_config(primary_layer_keys);
-    let layers = if base.media_layer_default.unwrap(){ [media_layer, fkey_layer] } else { [fkey_layer, media_layer] };
+    for action in base.swipe_left.iter().chain(&base.swipe_right) {
+        if let Some(name) = action.target() {
+            find_layer(&layers, name);
+        }
+    }
+    // a named theme goes over the defaults, and the keys set along with it over the theme
+    let mut theme = base.theme.unwrap();
+    if let Some(name) = user_theme.name.as_ref().or(theme.name.as_ref()) {
+        theme = load_theme(name).over(theme);
+    }
+    let theme = build_theme(user_theme.over(theme));
     let cfg = Config {
         show_button_outlines: base.show_button_outlines.unwrap(),
         enable_pixel_shift: base.enable_pixel_shift.unwrap(),
         adaptive_brightness: base.adaptive_brightness.unwrap(),
         font_face: load_font(&base.font_template.unwrap()),
-        active_brightness: base.active_brightness.unwrap()
+        active_brightness: base.active_brightness.unwrap(),
+        default_layer,
+        fn_layer,
+        modifier_layers,
+        fn_lock_double_tap: base.fn_lock_double_tap.unwrap(),
+        fn_lock_chord: base.fn_lock_chord.unwrap_or_default(),
+        command_user: base.command_user.unwrap(),
+        control_socket_group: base.control_socket_group.unwrap(),
+        swipe_left: base.swipe_left,
+        swipe_right: base.swipe_right,
+        app_layers,
+        theme,
     };
     (cfg, layers)
 }
 
 pub struct ConfigManager {
     inotify_fd: Inotify,
-    watch_desc: Option<WatchDescriptor>
+    watch_desc: Option<WatchDescriptor>,
+    reload_requested: bool
 }
 
 fn arm_inotify(inotify_fd: &Inotify) -> Option<WatchDescriptor> {
-    let flags = AddWatchFlags::IN_MOVED_TO | AddWatchFlags::IN_CLOSE | AddWatchFlags::IN_ONESHOT;
-    match inotify_fd.add_watch(USER_CFG_PATH, flags) {
+    // not IN_CLOSE, as reading the config to reload it would then trigger the next reload
+    let flags = AddWatchFlags::IN_MOVED_TO | AddWatchFlags::IN_CLOSE_WRITE | AddWatchFlags::IN_ONESHOT;
+    match inotify_fd.add_watch(&system_path(USER_CFG_PATH), flags) {
         Ok(wd) => Some(wd),
         Err(Errno::ENOENT) => None,
         e => Some(e.unwrap())
@@ -116,34 +418,40 @@ impl ConfigManager {
         let inotify_fd = Inotify::init(InitFlags::IN_NONBLOCK).unwrap();
         let watch_desc = arm_inotify(&inotify_fd);
         ConfigManager {
-            inotify_fd, watch_desc
+            inotify_fd, watch_desc,
+            reload_requested: false
         }
     }
-    pub fn load_config(&self, width: u16) -> (Config, [FunctionLayer; 2]) {
+    pub fn load_config(&self, width: u16) -> (Config, Vec<FunctionLayer>) {
         load_config(width)
     }
-    pub fn update_config(&mut self, cfg: &mut Config, layers: &mut [FunctionLayer; 2], width: u16) -> bool {
+    // Returns the new config and layers if it has to be reloaded
+    pub fn update_config(&mut self, width: u16) -> Option<(Config, Vec<FunctionLayer>)> {
+        let mut reload = self.reload_requested;
+        self.reload_requested = false;
         if self.watch_desc.is_none() {
             self.watch_desc = arm_inotify(&self.inotify_fd);
-            return false;
-        }
-        let evts = match self.inotify_fd.read_events() {
-            Ok(e) => e,
-            Err(Errno::EAGAIN) => Vec::new(),
-            r => r.unwrap(),
-        };
-        let mut ret = false;
-        for evt in evts {
-            if evt.wd != self.watch_desc.unwrap() {
-                continue
+        } else {
+            let evts = match self.inotify_fd.read_events() {
+                Ok(e) => e,
+                Err(Errno::EAGAIN) => Vec::new(),
+                r => r.unwrap(),
+            };
+            for evt in evts {
+                if evt.wd != self.watch_desc.unwrap() {
+                    continue
+                }
+                reload = true;
+                self.watch_desc = arm_inotify(&self.inotify_fd);
             }
-            let parts = load_config(width);
-            *cfg = parts.0;
-            *layers = parts.1;
-            ret = true;
-            self.watch_desc = arm_inotify(&self.inotify_fd);
         }
-        ret
+        if !reload {
+            return None;
+        }
+        Some(load_config(width))
+    }
+    pub fn request_reload(&mut self) {
+        self.reload_requested = true;
     }
     pub fn fd(&self) -> &impl AsFd {
         &self.inotify_fd
@@ -0,0 +1,240 @@
+use std::{
+    fs::{self, Permissions},
+    io::{ErrorKind, IoSliceMut, Write},
+    os::{
+        fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::{fs::{chown, PermissionsExt}, net::{UnixListener, UnixStream}},
+    },
+};
+use nix::{
+    cmsg_space,
+    errno::Errno,
+    sys::{
+        epoll::{Epoll, EpollEvent, EpollFlags},
+        socket::{recvmsg, ControlMessageOwned, MsgFlags},
+    },
+    unistd::Group,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::{Deserialize, Deserializer, Serialize, de::Error};
+use crate::config::{system_path, Color};
+
+pub const CONTROL_SOCKET_PATH: &str = "/run/tiny-dfr/control.sock";
+const CONTROL_TOKEN: u64 = 4;
+// of set_image data, once decoded
+const MAX_IMAGE_LEN: usize = 1024 * 1024;
+// clients sending longer lines are dropped, this leaves room for the largest set_image request
+const MAX_LINE_LEN: usize = 64 * 1024 + MAX_IMAGE_LEN.div_ceil(3) * 4;
+// and so are those that leave this much of their replies unread
+const MAX_QUEUED_LEN: usize = 1024 * 1024;
+
+#[derive(Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    Status,
+    Layer { name: String },
+    // Taps a button, by index, in the given layer or the active one if not set
+    Press { button: usize, layer: Option<String> },
+    Brightness { value: u32 },
+    Reload,
+    SetText { layer: String, button: usize, text: String },
+    SetIcon { layer: String, button: usize, icon: String, theme: Option<String> },
+    // png or svg file contents, base64 encoded
+    SetImage { layer: String, button: usize, #[serde(deserialize_with = "from_base64")] data: Vec<u8> },
+    // unset to go back to the default color
+    SetColor { layer: String, button: usize, color: Option<Color> },
+    // for when the state a toggle button reflects is changed by something else
+    SetToggled { layer: String, button: usize, on: bool },
+    // Sent by a context provider when the focused app changes, empty if none is
+    Focus { app: String },
+    // Hands over a connection to the sway or i3 IPC socket, passed along with the request
+    WorkspaceSocket { #[serde(skip)] socket: Option<OwnedFd> },
+    // Hands over a connection to the session bus, opened by the given user
+    MediaBus { uid: u32, #[serde(skip)] socket: Option<OwnedFd> },
+    // The album art found at the url a player gave, base64 encoded
+    MediaArt { url: String, #[serde(deserialize_with = "from_base64")] data: Vec<u8> },
+}
+
+fn from_base64<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error> where D: Deserializer<'de> {
+    let data = BASE64.decode(String::deserialize(deserializer)?).map_err(D::Error::custom)?;
+    if data.len() > MAX_IMAGE_LEN {
+        return Err(D::Error::custom(format!("image is larger than {MAX_IMAGE_LEN} bytes")));
+    }
+    Ok(data)
+}
+
+#[derive(Serialize)]
+pub struct LayerStatus {
+    pub name: String,
+    pub buttons: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct Status {
+    pub active_layer: String,
+    pub fn_locked: bool,
+    pub backlight: u32,
+    pub idle: bool,
+    pub active_brightness: u32,
+    pub focused_app: String,
+    pub layers: Vec<LayerStatus>,
+}
+
+#[derive(Serialize)]
+struct Reply<'a> {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<&'a Status>,
+}
+
+struct Client {
+    id: u64,
+    stream: UnixStream,
+    buf: Vec<u8>,
+    // replies that did not fit in the socket yet
+    out: Vec<u8>,
+    // received with SCM_RIGHTS, waiting for the request they go with
+    fds: Vec<OwnedFd>,
+}
+
+impl Client {
+    // Writes as much of the queued replies as the socket takes, returns false if the client went away
+    fn flush(&mut self) -> bool {
+        while !self.out.is_empty() {
+            match self.stream.write(&self.out) {
+                Ok(0) => return false,
+                Ok(len) => {
+                    self.out.drain(..len);
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => return false
+            }
+        }
+        true
+    }
+}
+
+// Line-delimited JSON protocol, every request line gets exactly one reply line
+pub struct ControlServer {
+    listener: UnixListener,
+    clients: Vec<Client>,
+    next_id: u64,
+}
+
+impl ControlServer {
+    pub fn new(group: &str) -> ControlServer {
+        let path = &system_path(CONTROL_SOCKET_PATH);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => panic!("Failed to remove stale control socket: {e}"),
+            _ => {}
+        }
+        let listener = UnixListener::bind(path).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let gid = match Group::from_name(group).unwrap() {
+            Some(group) => group.gid.as_raw(),
+            None => panic!("Invalid configuration, group {group} does not exist")
+        };
+        chown(path, None, Some(gid)).unwrap();
+        fs::set_permissions(path, Permissions::from_mode(0o660)).unwrap();
+        ControlServer {
+            listener,
+            clients: Vec::new(),
+            next_id: 0,
+        }
+    }
+    pub fn fd(&self) -> &impl AsFd {
+        &self.listener
+    }
+    // Accepts new clients and returns all complete requests received since the last call
+    pub fn update(&mut self, epoll: &Epoll) -> Vec<(Option<u64>, Result<Request, String>)> {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(true).unwrap();
+                    // edge triggered, so that being writable only wakes the loop when room for queued replies frees up
+                    let flags = EpollFlags::EPOLLIN | EpollFlags::EPOLLOUT | EpollFlags::EPOLLET;
+                    epoll.add(stream.as_fd(), EpollEvent::new(flags, CONTROL_TOKEN)).unwrap();
+                    self.clients.push(Client { id: self.next_id, stream, buf: Vec::new(), out: Vec::new(), fds: Vec::new() });
+                    self.next_id += 1;
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    println!("Failed to accept control connection: {e}");
+                    break;
+                }
+            }
+        }
+        let mut requests = Vec::new();
+        self.clients.retain_mut(|client| {
+            if !client.flush() {
+                return false;
+            }
+            let mut chunk = [0u8; 4096];
+            loop {
+                let mut cmsg = cmsg_space!([RawFd; 1]);
+                let mut iov = [IoSliceMut::new(&mut chunk)];
+                let len = match recvmsg::<()>(client.stream.as_raw_fd(), &mut iov, Some(&mut cmsg), MsgFlags::MSG_CMSG_CLOEXEC) {
+                    Ok(msg) => {
+                        for cmsg in msg.cmsgs().into_iter().flatten() {
+                            if let ControlMessageOwned::ScmRights(fds) = cmsg {
+                                client.fds.extend(fds.into_iter().map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }));
+                            }
+                        }
+                        msg.bytes
+                    },
+                    Err(Errno::EAGAIN) => break,
+                    Err(_) => return false
+                };
+                // closing the stream also removes it from the epoll set
+                if len == 0 {
+                    return false;
+                }
+                client.buf.extend_from_slice(&chunk[..len]);
+                if !chunk[..len].contains(&b'\n') {
+                    if client.buf.len() > MAX_LINE_LEN {
+                        println!("Control client sent a line longer than {MAX_LINE_LEN} bytes, dropping it");
+                        return false;
+                    }
+                    continue;
+                }
+                while let Some(pos) = client.buf.iter().position(|b| *b == b'\n') {
+                    let line = client.buf.drain(..=pos).collect::<Vec<_>>();
+                    if line.iter().all(|b| b.is_ascii_whitespace()) {
+                        continue;
+                    }
+                    let mut request = serde_json::from_slice(&line).map_err(|e| e.to_string());
+                    if let Ok(Request::WorkspaceSocket { socket } | Request::MediaBus { socket, .. }) = &mut request {
+                        *socket = (!client.fds.is_empty()).then(|| client.fds.remove(0));
+                    }
+                    requests.push((Some(client.id), request));
+                }
+            }
+            true
+        });
+        requests
+    }
+    fn send(&mut self, client: u64, reply: &Reply) {
+        let Some(pos) = self.clients.iter().position(|c| c.id == client) else {
+            return;
+        };
+        let mut line = serde_json::to_string(reply).unwrap();
+        line.push('\n');
+        let client = &mut self.clients[pos];
+        client.out.extend_from_slice(line.as_bytes());
+        // what does not fit is sent once the socket is writable again, see update
+        if client.out.len() > MAX_QUEUED_LEN || !client.flush() {
+            self.clients.remove(pos);
+        }
+    }
+    pub fn reply(&mut self, client: u64, result: &Result<Option<Status>, String>) {
+        let reply = match result {
+            Ok(status) => Reply { ok: true, error: None, status: status.as_ref() },
+            Err(err) => Reply { ok: false, error: Some(err), status: None }
+        };
+        self.send(client, &reply);
+    }
+}
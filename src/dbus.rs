@@ -0,0 +1,251 @@
+use std::{
+    os::fd::AsFd,
+    sync::{mpsc::{self, Receiver, Sender}, Arc, Mutex},
+};
+use anyhow::Result;
+use nix::sys::eventfd::{EfdFlags, EventFd};
+use zbus::{
+    blocking::{connection, object_server::InterfaceRef, Connection},
+    fdo, interface,
+    object_server::SignalEmitter,
+};
+use crate::FunctionLayer;
+use crate::config::Color;
+use crate::control::Request;
+
+const BUS_NAME: &str = "org.asahi.TinyDfr";
+const OBJECT_PATH: &str = "/org/asahi/TinyDfr";
+
+#[derive(Default)]
+struct Properties {
+    active_layer: String,
+    brightness: u32,
+    idle: bool,
+    // name and button count of every layer, to validate method arguments
+    layers: Vec<(String, usize)>,
+}
+
+// Method calls are handled on zbus' own thread, so they are only validated
+// there and then queued for the main loop, which is woken up through an eventfd.
+struct TinyDfr {
+    props: Arc<Mutex<Properties>>,
+    requests: Sender<Request>,
+    wake: Arc<EventFd>,
+}
+
+impl TinyDfr {
+    fn queue(&self, request: Request) -> fdo::Result<()> {
+        self.requests.send(request).map_err(|e| fdo::Error::Failed(e.to_string()))?;
+        self.wake.write(1).map_err(|e| fdo::Error::Failed(e.to_string()))?;
+        Ok(())
+    }
+    fn check_button(&self, layer: &str, button: u32) -> fdo::Result<()> {
+        let props = self.props.lock().unwrap();
+        match props.layers.iter().find(|(name, _)| name == layer) {
+            Some((_, count)) if (button as usize) < *count => Ok(()),
+            Some(_) => Err(fdo::Error::InvalidArgs(format!("layer {layer} has no button {button}"))),
+            None => Err(fdo::Error::InvalidArgs(format!("no layer named {layer}")))
+        }
+    }
+}
+
+#[interface(name = "org.asahi.TinyDfr1")]
+impl TinyDfr {
+    fn switch_layer(&self, name: &str) -> fdo::Result<()> {
+        if !self.props.lock().unwrap().layers.iter().any(|(n, _)| n == name) {
+            return Err(fdo::Error::InvalidArgs(format!("no layer named {name}")));
+        }
+        self.queue(Request::Layer { name: name.into() })
+    }
+    fn set_button_text(&self, layer: &str, button: u32, text: &str) -> fdo::Result<()> {
+        self.check_button(layer, button)?;
+        self.queue(Request::SetText { layer: layer.into(), button: button as usize, text: text.into() })
+    }
+    // An empty theme looks the icon up in the same places as icons without a Theme in the config
+    fn set_button_icon(&self, layer: &str, button: u32, icon: &str, theme: &str) -> fdo::Result<()> {
+        self.check_button(layer, button)?;
+        let theme = if theme.is_empty() { None } else { Some(theme.into()) };
+        self.queue(Request::SetIcon { layer: layer.into(), button: button as usize, icon: icon.into(), theme })
+    }
+    // The data is the contents of a png or svg file
+    fn set_button_image(&self, layer: &str, button: u32, data: Vec<u8>) -> fdo::Result<()> {
+        self.check_button(layer, button)?;
+        self.queue(Request::SetImage { layer: layer.into(), button: button as usize, data })
+    }
+    // An empty color goes back to the default one
+    fn set_button_color(&self, layer: &str, button: u32, color: &str) -> fdo::Result<()> {
+        self.check_button(layer, button)?;
+        let color = if color.is_empty() {
+            None
+        } else {
+            Some(Color::parse(color).map_err(fdo::Error::InvalidArgs)?)
+        };
+        self.queue(Request::SetColor { layer: layer.into(), button: button as usize, color })
+    }
+    fn set_focused_app(&self, app: &str) -> fdo::Result<()> {
+        self.queue(Request::Focus { app: app.into() })
+    }
+    fn set_button_toggled(&self, layer: &str, button: u32, on: bool) -> fdo::Result<()> {
+        self.check_button(layer, button)?;
+        self.queue(Request::SetToggled { layer: layer.into(), button: button as usize, on })
+    }
+    #[zbus(property)]
+    fn active_layer(&self) -> String {
+        self.props.lock().unwrap().active_layer.clone()
+    }
+    #[zbus(property)]
+    fn brightness(&self) -> u32 {
+        self.props.lock().unwrap().brightness
+    }
+    #[zbus(property)]
+    fn idle(&self) -> bool {
+        self.props.lock().unwrap().idle
+    }
+    #[zbus(signal)]
+    async fn button_pressed(emitter: &SignalEmitter<'_>, layer: &str, button: u32) -> zbus::Result<()>;
+}
+
+// Connects to the bus in DBUS_SYSTEM_BUS_ADDRESS if set, so that it can be
+// run against a private dbus-daemon instance.
+pub struct DbusService {
+    _conn: Connection,
+    iface: InterfaceRef<TinyDfr>,
+    props: Arc<Mutex<Properties>>,
+    requests: Receiver<Request>,
+    wake: Arc<EventFd>,
+}
+
+impl DbusService {
+    pub fn new() -> Result<DbusService> {
+        DbusService::on(connection::Builder::system()?)
+    }
+    fn on(bus: connection::Builder) -> Result<DbusService> {
+        let props = Arc::new(Mutex::new(Properties::default()));
+        let wake = Arc::new(EventFd::from_flags(EfdFlags::EFD_NONBLOCK)?);
+        let (sender, requests) = mpsc::channel();
+        let iface = TinyDfr { props: props.clone(), requests: sender, wake: wake.clone() };
+        let conn = bus
+            .name(BUS_NAME)?
+            .serve_at(OBJECT_PATH, iface)?
+            .build()?;
+        let iface = conn.object_server().interface::<_, TinyDfr>(OBJECT_PATH)?;
+        Ok(DbusService { _conn: conn, iface, props, requests, wake })
+    }
+    pub fn fd(&self) -> &impl AsFd {
+        self.wake.as_ref()
+    }
+    pub fn set_layers(&self, layers: &[FunctionLayer]) {
+        self.props.lock().unwrap().layers = layers.iter().map(|l| (l.name.clone(), l.buttons.len())).collect();
+    }
+    // Returns the requests received since the last call
+    pub fn update(&self) -> Vec<Request> {
+        _ = self.wake.read();
+        self.requests.try_iter().collect()
+    }
+    pub fn update_properties(&self, active_layer: &str, brightness: u32, idle: bool) {
+        // the *_changed methods read the properties back, so the lock is released first
+        let (layer_changed, brightness_changed, idle_changed) = {
+            let mut props = self.props.lock().unwrap();
+            let changed = (props.active_layer != active_layer, props.brightness != brightness, props.idle != idle);
+            props.active_layer = active_layer.into();
+            props.brightness = brightness;
+            props.idle = idle;
+            changed
+        };
+        let emitter = self.iface.signal_emitter();
+        let iface = self.iface.get();
+        let mut res = Ok(());
+        if layer_changed {
+            res = res.and(zbus::block_on(iface.active_layer_changed(emitter)));
+        }
+        if brightness_changed {
+            res = res.and(zbus::block_on(iface.brightness_changed(emitter)));
+        }
+        if idle_changed {
+            res = res.and(zbus::block_on(iface.idle_changed(emitter)));
+        }
+        if let Err(err) = res {
+            println!("Failed to emit D-Bus property change: {err}");
+        }
+    }
+    pub fn button_pressed(&self, layer: &str, button: usize) {
+        if let Err(err) = zbus::block_on(TinyDfr::button_pressed(self.iface.signal_emitter(), layer, button as u32)) {
+            println!("Failed to emit D-Bus button press: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, thread, time::Duration};
+    use zbus::blocking::{connection, Connection, Proxy};
+    use crate::test_bus::PrivateBus;
+    use crate::{Button, ButtonAction, FunctionLayer};
+    use crate::control::Request;
+    use super::{DbusService, BUS_NAME, OBJECT_PATH};
+
+    fn layer(name: &str, count: usize) -> FunctionLayer {
+        FunctionLayer {
+            name: name.into(),
+            buttons: (0..count).map(|i| (i, Button::new_text(i.to_string(), ButtonAction::None))).collect(),
+            virtual_button_count: count,
+        }
+    }
+
+    fn connect(bus: &PrivateBus) -> (DbusService, Connection) {
+        let service = DbusService::on(connection::Builder::address(bus.address.as_str()).unwrap()).unwrap();
+        service.set_layers(&[layer("fkeys", 12), layer("media", 3)]);
+        let client = connection::Builder::address(bus.address.as_str()).unwrap().build().unwrap();
+        (service, client)
+    }
+
+    fn proxy(client: &Connection) -> Proxy<'static> {
+        Proxy::new(client, BUS_NAME, OBJECT_PATH, "org.asahi.TinyDfr1").unwrap()
+    }
+
+    #[test]
+    fn properties() {
+        let Some(bus) = PrivateBus::start() else {
+            return;
+        };
+        let (service, client) = connect(&bus);
+        service.update_properties("media", 128, true);
+        let proxy = proxy(&client);
+        assert_eq!(proxy.get_property::<String>("ActiveLayer").unwrap(), "media");
+        assert_eq!(proxy.get_property::<u32>("Brightness").unwrap(), 128);
+        assert!(proxy.get_property::<bool>("Idle").unwrap());
+    }
+
+    #[test]
+    fn switch_layer() {
+        let Some(bus) = PrivateBus::start() else {
+            return;
+        };
+        let (service, client) = connect(&bus);
+        let proxy = proxy(&client);
+        proxy.call_method("SwitchLayer", &("media",)).unwrap();
+        let requests = service.update();
+        assert!(matches!(requests.as_slice(), [Request::Layer { name }] if name == "media"));
+
+        assert!(proxy.call_method("SwitchLayer", &("nope",)).is_err());
+        assert!(proxy.call_method("SetButtonText", &("media", 3u32, "hi")).is_err());
+        assert!(service.update().is_empty());
+    }
+
+    #[test]
+    fn button_pressed() {
+        let Some(bus) = PrivateBus::start() else {
+            return;
+        };
+        let (service, client) = connect(&bus);
+        let mut signals = proxy(&client).receive_signal("ButtonPressed").unwrap();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let msg = signals.next().unwrap();
+            sender.send(msg.body().deserialize::<(String, u32)>().unwrap()).unwrap();
+        });
+        service.button_pressed("media", 2);
+        let (layer, button) = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!((layer.as_str(), button), ("media", 2));
+    }
+}
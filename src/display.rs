@@ -1,5 +1,6 @@
 use std::{
     fs::{File, OpenOptions, self},
+    ops::DerefMut,
     os::unix::io::{AsFd, BorrowedFd},
     path::Path,
 };
@@ -7,11 +8,22 @@ use drm::{
     ClientCapability, Device as DrmDevice, buffer::DrmFourcc,
     control::{
         connector, Device as ControlDevice, property, ResourceHandle, atomic, AtomicCommitFlags,
-        dumbbuffer::{DumbBuffer, DumbMapping}, framebuffer, ClipRect, Mode
+        dumbbuffer::DumbBuffer, framebuffer, ClipRect, Mode
     }
 };
 use anyhow::{Result, anyhow};
 
+// Where frames are drawn to, the touch bar itself or somewhere offscreen
+pub trait DisplayBackend {
+    // The size of the panel, which is taller than it is wide
+    fn mode(&self) -> (u16, u16);
+    // The size of the buffer that map returns, which can be wider than the panel
+    fn fb_info(&self) -> Result<(u32, u32)>;
+    // The frame, in XRGB8888 rows of fb_info's width
+    fn map(&mut self) -> Result<Box<dyn DerefMut<Target = [u8]> + '_>>;
+    fn dirty(&mut self, clips: &[ClipRect]) -> Result<()>;
+}
+
 struct Card(File);
 impl AsFd for Card {
     fn as_fd(&self) -> BorrowedFd<'_> {
@@ -190,16 +202,19 @@ impl DrmBackend {
         }
         Err(anyhow!("No touchbar device found, attempted: [\n    {}\n]", errors.join(",\n    ")))
     }
-    pub fn mode(&self) -> Mode {
-        self.mode
+}
+
+impl DisplayBackend for DrmBackend {
+    fn mode(&self) -> (u16, u16) {
+        self.mode.size()
     }
-    pub fn fb_info(&self) -> Result<framebuffer::Info> {
-        Ok(self.card.get_framebuffer(self.fb)?)
+    fn fb_info(&self) -> Result<(u32, u32)> {
+        Ok(self.card.get_framebuffer(self.fb)?.size())
     }
-    pub fn dirty(&self, clips: &[ClipRect]) -> Result<()> {
+    fn dirty(&mut self, clips: &[ClipRect]) -> Result<()> {
         Ok(self.card.dirty_framebuffer(self.fb, clips)?)
     }
-    pub fn map(&mut self) -> Result<DumbMapping> {
-        Ok(self.card.map_dumb_buffer(&mut self.db)?)
+    fn map(&mut self) -> Result<Box<dyn DerefMut<Target = [u8]> + '_>> {
+        Ok(Box::new(self.card.map_dumb_buffer(&mut self.db)?))
     }
 }
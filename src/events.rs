@@ -0,0 +1,192 @@
+use std::{
+    collections::VecDeque,
+    fs::{read_to_string, File, OpenOptions},
+    os::{
+        fd::{AsFd, BorrowedFd, OwnedFd},
+        unix::fs::OpenOptionsExt
+    },
+    path::Path,
+    time::Instant,
+};
+use anyhow::Result;
+use input::{
+    Libinput, LibinputInterface, Device as InputDevice,
+    event::{
+        Event, device::DeviceEvent, EventTrait,
+        keyboard::{KeyboardEvent, KeyboardEventTrait, KeyState},
+        switch::{Switch, SwitchEvent, SwitchState},
+        touch::{TouchEvent, TouchEventPosition, TouchEventSlot}
+    }
+};
+use input_linux::Key;
+use libc::{O_ACCMODE, O_RDONLY, O_RDWR, O_WRONLY};
+use serde::Deserialize;
+use crate::uinput;
+
+// What the daemon reacts to, wherever it comes from
+#[derive(Deserialize, Clone, Copy)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum InputEvent {
+    // anything else done on an input device, which only keeps the backlight on
+    Activity,
+    Key { key: Key, pressed: bool },
+    Lid { closed: bool },
+    // touches on the touch bar, in pixels along and across it
+    TouchDown { slot: u32, x: f64, y: f64 },
+    TouchMotion { slot: u32, x: f64, y: f64 },
+    TouchUp { slot: u32 },
+}
+
+pub trait InputSource {
+    // Become readable when there are new events
+    fn fds(&self) -> Vec<BorrowedFd<'_>>;
+    // The events since the last call, for a touch bar of the given size
+    fn events(&mut self, width: u16, height: u16) -> Vec<InputEvent>;
+    // How long until there are events that no fd signals
+    fn next_timeout_ms(&self) -> i32;
+    // Whether there will be no more events
+    fn finished(&self) -> bool;
+}
+
+struct Interface;
+
+impl LibinputInterface for Interface {
+    fn open_restricted(&mut self, path: &Path, flags: i32) -> Result<OwnedFd, i32> {
+        let mode = flags & O_ACCMODE;
+
+        OpenOptions::new()
+            .custom_flags(flags)
+            .read(mode == O_RDONLY || mode == O_RDWR)
+            .write(mode == O_WRONLY || mode == O_RDWR)
+            .open(path)
+            .map(|file| file.into())
+            .map_err(|err| err.raw_os_error().unwrap())
+    }
+    fn close_restricted(&mut self, fd: OwnedFd) {
+        _ = File::from(fd);
+    }
+}
+
+// The touch bar's digitizer from its own seat, and the keyboard and lid switch from seat0
+pub struct LibinputSource {
+    input_tb: Libinput,
+    input_main: Libinput,
+    digitizer: Option<InputDevice>,
+}
+
+impl LibinputSource {
+    pub fn new() -> LibinputSource {
+        let mut input_tb = Libinput::new_with_udev(Interface);
+        let mut input_main = Libinput::new_with_udev(Interface);
+        input_tb.udev_assign_seat("seat-touchbar").unwrap();
+        input_main.udev_assign_seat("seat0").unwrap();
+        LibinputSource { input_tb, input_main, digitizer: None }
+    }
+}
+
+impl InputSource for LibinputSource {
+    fn fds(&self) -> Vec<BorrowedFd<'_>> {
+        vec![self.input_main.as_fd(), self.input_tb.as_fd()]
+    }
+    fn events(&mut self, width: u16, height: u16) -> Vec<InputEvent> {
+        self.input_tb.dispatch().unwrap();
+        self.input_main.dispatch().unwrap();
+        let mut events = Vec::new();
+        for event in &mut self.input_tb.clone().chain(self.input_main.clone()) {
+            // the keys the buttons send come back on seat0, and must not be taken for the user's own
+            if event.device().name() == uinput::DEVICE_NAME {
+                continue;
+            }
+            let event = match event {
+                Event::Device(DeviceEvent::Added(evt)) => {
+                    let dev = evt.device();
+                    if dev.name().contains(" Touch Bar") {
+                        self.digitizer = Some(dev);
+                    }
+                    continue;
+                },
+                Event::Keyboard(KeyboardEvent::Key(key)) => match Key::from_code(key.key() as u16) {
+                    Ok(code) => InputEvent::Key { key: code, pressed: key.key_state() == KeyState::Pressed },
+                    Err(_) => InputEvent::Activity
+                },
+                Event::Switch(SwitchEvent::Toggle(toggle)) if toggle.switch() == Some(Switch::Lid) => {
+                    InputEvent::Lid { closed: toggle.switch_state() == SwitchState::On }
+                },
+                Event::Touch(te) if Some(te.device()) == self.digitizer => match te {
+                    TouchEvent::Down(dn) => InputEvent::TouchDown {
+                        slot: dn.seat_slot(),
+                        x: dn.x_transformed(width as u32),
+                        y: dn.y_transformed(height as u32),
+                    },
+                    TouchEvent::Motion(mtn) => InputEvent::TouchMotion {
+                        slot: mtn.seat_slot(),
+                        x: mtn.x_transformed(width as u32),
+                        y: mtn.y_transformed(height as u32),
+                    },
+                    TouchEvent::Up(up) => InputEvent::TouchUp { slot: up.seat_slot() },
+                    _ => InputEvent::Activity
+                },
+                Event::Keyboard(_) | Event::Pointer(_) | Event::Gesture(_) | Event::Touch(_) => InputEvent::Activity,
+                _ => continue
+            };
+            events.push(event);
+        }
+        events
+    }
+    fn next_timeout_ms(&self) -> i32 {
+        i32::MAX
+    }
+    fn finished(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Deserialize)]
+struct ScriptedEvent {
+    // milliseconds since the script started
+    at: u64,
+    #[serde(flatten)]
+    event: InputEvent,
+}
+
+// Plays back a JSON timeline of events, for driving the daemon without any
+// input devices. Touches are given in pixels, so the size of the bar is unused.
+pub struct ScriptedSource {
+    start: Instant,
+    events: VecDeque<ScriptedEvent>,
+}
+
+impl ScriptedSource {
+    pub fn new(path: &Path) -> Result<ScriptedSource> {
+        let mut events = serde_json::from_str::<Vec<ScriptedEvent>>(&read_to_string(path)?)?;
+        events.sort_by_key(|event| event.at);
+        Ok(ScriptedSource { start: Instant::now(), events: events.into() })
+    }
+    fn elapsed_ms(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+}
+
+impl InputSource for ScriptedSource {
+    fn fds(&self) -> Vec<BorrowedFd<'_>> {
+        Vec::new()
+    }
+    fn events(&mut self, _width: u16, _height: u16) -> Vec<InputEvent> {
+        let elapsed = self.elapsed_ms();
+        let mut events = Vec::new();
+        while self.events.front().is_some_and(|event| event.at <= elapsed) {
+            events.push(self.events.pop_front().unwrap().event);
+        }
+        events
+    }
+    fn next_timeout_ms(&self) -> i32 {
+        match self.events.front() {
+            Some(event) => event.at.saturating_sub(self.elapsed_ms()).min(i32::MAX as u64) as i32,
+            // wakes the daemon right away to exit, once the last event was handled
+            None => 0
+        }
+    }
+    fn finished(&self) -> bool {
+        self.events.is_empty()
+    }
+}
@@ -0,0 +1,282 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+// A second tap on the same target within this long makes it a double tap
+const DOUBLE_TAP_INTERVAL: Duration = Duration::from_millis(300);
+// How far a touch has to move sideways to count as a swipe
+const SWIPE_DISTANCE_PX: f64 = 80.0;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Gesture {
+    Tap,
+    DoubleTap,
+    LongPress,
+    SwipeLeft,
+    SwipeRight,
+}
+
+// Which gestures a touch can turn into, besides taps and swipes
+#[derive(Clone, Copy, Default)]
+pub struct GestureOptions {
+    pub long_press: Option<Duration>,
+    // taps are delayed until it is clear no second one follows
+    pub double_tap: bool,
+}
+
+// Whether a touch that moved from start_x to x was swiped
+pub fn swipe(start_x: f64, x: f64) -> Option<Gesture> {
+    if (x - start_x).abs() < SWIPE_DISTANCE_PX {
+        return None;
+    }
+    Some(if x < start_x { Gesture::SwipeLeft } else { Gesture::SwipeRight })
+}
+
+pub struct GestureEvent<T> {
+    pub slot: u32,
+    pub target: T,
+    pub gesture: Gesture,
+    // where the touch was last seen
+    pub x: f64,
+}
+
+struct TrackedTouch<T> {
+    target: T,
+    options: GestureOptions,
+    start_x: f64,
+    x: f64,
+    start: Instant,
+    // whether the touch already turned into a gesture
+    recognized: bool,
+}
+
+struct PendingTap<T> {
+    slot: u32,
+    target: T,
+    x: f64,
+    time: Instant,
+}
+
+// Classifies touches per seat slot into gestures. It does not read the time
+// itself, every call is given it instead, so that it does not depend on
+// libinput or on the clock.
+pub struct GestureRecognizer<T> {
+    touches: HashMap<u32, TrackedTouch<T>>,
+    pending_tap: Option<PendingTap<T>>,
+}
+
+impl<T: Copy + PartialEq> GestureRecognizer<T> {
+    pub fn new() -> GestureRecognizer<T> {
+        GestureRecognizer {
+            touches: HashMap::new(),
+            pending_tap: None,
+        }
+    }
+    fn flush_pending_tap(&mut self, events: &mut Vec<GestureEvent<T>>) {
+        if let Some(tap) = self.pending_tap.take() {
+            events.push(GestureEvent { slot: tap.slot, target: tap.target, gesture: Gesture::Tap, x: tap.x });
+        }
+    }
+    pub fn down(&mut self, slot: u32, target: T, x: f64, time: Instant, options: GestureOptions) -> Vec<GestureEvent<T>> {
+        let mut events = Vec::new();
+        if self.pending_tap.as_ref().is_some_and(|tap| tap.target != target) {
+            self.flush_pending_tap(&mut events);
+        }
+        self.touches.insert(slot, TrackedTouch { target, options, start_x: x, x, start: time, recognized: false });
+        events
+    }
+    pub fn motion(&mut self, slot: u32, x: f64) -> Vec<GestureEvent<T>> {
+        let Some(touch) = self.touches.get_mut(&slot) else {
+            return Vec::new();
+        };
+        touch.x = x;
+        if touch.recognized {
+            return Vec::new();
+        }
+        let Some(gesture) = swipe(touch.start_x, x) else {
+            return Vec::new();
+        };
+        touch.recognized = true;
+        vec![GestureEvent { slot, target: touch.target, gesture, x }]
+    }
+    pub fn up(&mut self, slot: u32, time: Instant) -> Vec<GestureEvent<T>> {
+        let mut events = Vec::new();
+        let Some(touch) = self.touches.remove(&slot) else {
+            return events;
+        };
+        if touch.recognized {
+            return events;
+        }
+        if !touch.options.double_tap {
+            events.push(GestureEvent { slot, target: touch.target, gesture: Gesture::Tap, x: touch.x });
+            return events;
+        }
+        match self.pending_tap.take() {
+            Some(tap) if tap.target == touch.target && time - tap.time < DOUBLE_TAP_INTERVAL => {
+                events.push(GestureEvent { slot, target: touch.target, gesture: Gesture::DoubleTap, x: touch.x });
+            },
+            tap => {
+                self.pending_tap = tap;
+                self.flush_pending_tap(&mut events);
+                self.pending_tap = Some(PendingTap { slot, target: touch.target, x: touch.x, time });
+            }
+        }
+        events
+    }
+    // Returns the gestures that are recognized by time passing, long presses
+    // and taps that were not followed by a second one
+    pub fn update(&mut self, time: Instant) -> Vec<GestureEvent<T>> {
+        let mut events = Vec::new();
+        for (slot, touch) in self.touches.iter_mut() {
+            if touch.recognized {
+                continue;
+            }
+            if touch.options.long_press.is_some_and(|threshold| time - touch.start >= threshold) {
+                touch.recognized = true;
+                events.push(GestureEvent { slot: *slot, target: touch.target, gesture: Gesture::LongPress, x: touch.x });
+            }
+        }
+        if self.pending_tap.as_ref().is_some_and(|tap| time - tap.time >= DOUBLE_TAP_INTERVAL) {
+            self.flush_pending_tap(&mut events);
+        }
+        events
+    }
+    // When update should be called next, if anything is waiting on time passing
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let long_presses = self.touches.values()
+            .filter(|touch| !touch.recognized)
+            .filter_map(|touch| touch.options.long_press.map(|threshold| touch.start + threshold));
+        let tap = self.pending_tap.as_ref().map(|tap| tap.time + DOUBLE_TAP_INTERVAL);
+        long_presses.chain(tap).min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOLD: Duration = Duration::from_millis(500);
+    const TAP_ONLY: GestureOptions = GestureOptions { long_press: None, double_tap: false };
+    const DOUBLE_TAP: GestureOptions = GestureOptions { long_press: None, double_tap: true };
+    const LONG_PRESS: GestureOptions = GestureOptions { long_press: Some(HOLD), double_tap: false };
+
+    fn gestures(events: Vec<GestureEvent<u32>>) -> Vec<(u32, u32, Gesture)> {
+        events.into_iter().map(|event| (event.slot, event.target, event.gesture)).collect()
+    }
+
+    fn ms(start: Instant, ms: u64) -> Instant {
+        start + Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn tap() {
+        let mut recognizer = GestureRecognizer::new();
+        let t = Instant::now();
+        assert!(recognizer.down(0, 7, 100.0, t, TAP_ONLY).is_empty());
+        assert!(recognizer.motion(0, 120.0).is_empty());
+        assert_eq!(gestures(recognizer.up(0, ms(t, 50))), [(0, 7, Gesture::Tap)]);
+        assert!(recognizer.next_deadline().is_none());
+    }
+
+    #[test]
+    fn double_tap() {
+        let mut recognizer = GestureRecognizer::new();
+        let t = Instant::now();
+        recognizer.down(0, 7, 100.0, t, DOUBLE_TAP);
+        assert!(recognizer.up(0, ms(t, 50)).is_empty());
+        assert_eq!(recognizer.next_deadline(), Some(ms(t, 50) + DOUBLE_TAP_INTERVAL));
+        assert!(recognizer.update(ms(t, 100)).is_empty());
+        recognizer.down(1, 7, 100.0, ms(t, 150), DOUBLE_TAP);
+        assert_eq!(gestures(recognizer.up(1, ms(t, 200))), [(1, 7, Gesture::DoubleTap)]);
+        assert!(recognizer.update(ms(t, 1000)).is_empty());
+    }
+
+    #[test]
+    fn double_tap_window_expires() {
+        let mut recognizer = GestureRecognizer::new();
+        let t = Instant::now();
+        recognizer.down(0, 7, 100.0, t, DOUBLE_TAP);
+        recognizer.up(0, ms(t, 50));
+        assert!(recognizer.update(ms(t, 349)).is_empty());
+        assert_eq!(gestures(recognizer.update(ms(t, 350))), [(0, 7, Gesture::Tap)]);
+        // so a second tap later on starts over
+        recognizer.down(0, 7, 100.0, ms(t, 400), DOUBLE_TAP);
+        assert!(recognizer.up(0, ms(t, 450)).is_empty());
+        assert_eq!(gestures(recognizer.update(ms(t, 750))), [(0, 7, Gesture::Tap)]);
+    }
+
+    #[test]
+    fn tap_on_another_target_ends_double_tap() {
+        let mut recognizer = GestureRecognizer::new();
+        let t = Instant::now();
+        recognizer.down(0, 7, 100.0, t, DOUBLE_TAP);
+        recognizer.up(0, ms(t, 50));
+        assert_eq!(gestures(recognizer.down(0, 8, 300.0, ms(t, 100), TAP_ONLY)), [(0, 7, Gesture::Tap)]);
+        assert_eq!(gestures(recognizer.up(0, ms(t, 150))), [(0, 8, Gesture::Tap)]);
+    }
+
+    #[test]
+    fn long_press() {
+        let mut recognizer = GestureRecognizer::new();
+        let t = Instant::now();
+        recognizer.down(0, 7, 100.0, t, LONG_PRESS);
+        assert_eq!(recognizer.next_deadline(), Some(t + HOLD));
+        assert!(recognizer.update(ms(t, 499)).is_empty());
+        assert_eq!(gestures(recognizer.update(ms(t, 500))), [(0, 7, Gesture::LongPress)]);
+        assert!(recognizer.update(ms(t, 1000)).is_empty());
+        assert!(recognizer.next_deadline().is_none());
+        // and lifting it afterwards is not a tap
+        assert!(recognizer.up(0, ms(t, 1200)).is_empty());
+    }
+
+    #[test]
+    fn short_press_is_a_tap() {
+        let mut recognizer = GestureRecognizer::new();
+        let t = Instant::now();
+        recognizer.down(0, 7, 100.0, t, LONG_PRESS);
+        assert_eq!(gestures(recognizer.up(0, ms(t, 499))), [(0, 7, Gesture::Tap)]);
+        assert!(recognizer.update(ms(t, 600)).is_empty());
+    }
+
+    #[test]
+    fn swipes() {
+        let mut recognizer = GestureRecognizer::new();
+        let t = Instant::now();
+        recognizer.down(0, 7, 500.0, t, LONG_PRESS);
+        assert!(recognizer.motion(0, 500.0 + SWIPE_DISTANCE_PX - 1.0).is_empty());
+        assert_eq!(gestures(recognizer.motion(0, 500.0 + SWIPE_DISTANCE_PX)), [(0, 7, Gesture::SwipeRight)]);
+        // only once per touch, and it is neither held nor tapped
+        assert!(recognizer.motion(0, 100.0).is_empty());
+        assert!(recognizer.update(ms(t, 1000)).is_empty());
+        assert!(recognizer.up(0, ms(t, 1000)).is_empty());
+
+        recognizer.down(0, 7, 500.0, ms(t, 2000), TAP_ONLY);
+        assert!(recognizer.motion(0, 500.0 - SWIPE_DISTANCE_PX + 1.0).is_empty());
+        assert_eq!(gestures(recognizer.motion(0, 500.0 - SWIPE_DISTANCE_PX)), [(0, 7, Gesture::SwipeLeft)]);
+        assert!(recognizer.up(0, ms(t, 2100)).is_empty());
+    }
+
+    #[test]
+    fn swipe_thresholds() {
+        assert_eq!(swipe(100.0, 100.0 + SWIPE_DISTANCE_PX - 0.5), None);
+        assert_eq!(swipe(100.0, 100.0 + SWIPE_DISTANCE_PX), Some(Gesture::SwipeRight));
+        assert_eq!(swipe(100.0, 100.0 - SWIPE_DISTANCE_PX + 0.5), None);
+        assert_eq!(swipe(100.0, 100.0 - SWIPE_DISTANCE_PX), Some(Gesture::SwipeLeft));
+    }
+
+    #[test]
+    fn two_slots() {
+        let mut recognizer = GestureRecognizer::new();
+        let t = Instant::now();
+        recognizer.down(0, 1, 100.0, t, LONG_PRESS);
+        recognizer.down(1, 2, 900.0, ms(t, 100), TAP_ONLY);
+        assert_eq!(gestures(recognizer.motion(1, 700.0)), [(1, 2, Gesture::SwipeLeft)]);
+        assert!(recognizer.motion(0, 110.0).is_empty());
+        assert_eq!(gestures(recognizer.update(ms(t, 500))), [(0, 1, Gesture::LongPress)]);
+        assert!(recognizer.up(1, ms(t, 600)).is_empty());
+        recognizer.down(1, 3, 1500.0, ms(t, 700), TAP_ONLY);
+        assert_eq!(gestures(recognizer.up(1, ms(t, 750))), [(1, 3, Gesture::Tap)]);
+        assert!(recognizer.up(0, ms(t, 800)).is_empty());
+    }
+}
@@ -0,0 +1,155 @@
+use std::{
+    env,
+    fs::{create_dir_all, read_to_string, File},
+    os::unix::fs::symlink,
+    path::Path,
+};
+use anyhow::Result;
+use cairo::{Format, ImageSurface};
+use tempfile::TempDir;
+use crate::config::{parse_config, Config};
+use crate::fonts::{FontConfig, Pattern};
+use crate::layout::Layout;
+use crate::offscreen::landscape;
+use crate::pixel_shift::{PIXEL_SHIFT_HEIGHT_PX, PIXEL_SHIFT_WIDTH_PX};
+use crate::FunctionLayer;
+
+// Where the daemon finds its files, see config::system_path
+const ROOT_ENV: &str = "TINY_DFR_ROOT";
+// Set to write the frames as the new reference images
+const UPDATE_ENV: &str = "TINY_DFR_UPDATE_GOLDEN";
+// The font the reference images were rendered with
+const REFERENCE_FONT: &str = "DejaVuSans-Bold.ttf";
+
+// The bars of the 13 inch MacBook Pros, without and with an escape key
+const GEOMETRIES: [(u16, u16); 2] = [(2008, 60), (2170, 60)];
+// A channel can differ by this much, for antialiasing that changes between cairo versions
+const CHANNEL_TOLERANCE: u8 = 8;
+// and this many pixels by more than that
+const MAX_DIFFERING_PIXELS: usize = 16;
+
+// Where a frame is drawn, without pixel shift and at both ends of it
+fn pixel_shifts() -> [Option<(f64, f64)>; 3] {
+    let (x, y) = ((PIXEL_SHIFT_WIDTH_PX / 2) as f64, (PIXEL_SHIFT_HEIGHT_PX / 2) as f64);
+    [None, Some((-x, -y)), Some((x, y))]
+}
+
+fn render(layer: &mut FunctionLayer, cfg: &Config, width: u16, height: u16, shift: Option<(f64, f64)>) -> Result<ImageSurface> {
+    let mut surface = ImageSurface::create(Format::ARgb32, height as i32, width as i32)?;
+    layer.draw(cfg, &Layout::new(width, height, shift, &cfg.theme), &surface, false, true);
+    let stride = surface.stride() as usize;
+    let frame = landscape(&surface.data()?, stride, (height, width));
+    frame
+}
+
+// The number of pixels that differ by more than the tolerance, or None if the sizes differ
+fn differing_pixels(actual: &mut ImageSurface, reference: &mut ImageSurface) -> Result<Option<usize>> {
+    if (actual.width(), actual.height()) != (reference.width(), reference.height()) {
+        return Ok(None);
+    }
+    let (width, height) = (actual.width() as usize, actual.height() as usize);
+    let (actual_stride, reference_stride) = (actual.stride() as usize, reference.stride() as usize);
+    let (actual, reference) = (actual.data()?, reference.data()?);
+    let mut count = 0;
+    for y in 0..height {
+        for x in 0..width {
+            let (a, r) = (y * actual_stride + x * 4, y * reference_stride + x * 4);
+            // the fourth byte is alpha, or unused, and the frames are opaque
+            if (0..3).any(|c| actual[a + c].abs_diff(reference[r + c]) > CHANNEL_TOLERANCE) {
+                count += 1;
+            }
+        }
+    }
+    Ok(Some(count))
+}
+
+// Returns whether the frame matches its reference, or writes it as the reference when updating
+fn check(mut actual: ImageSurface, dir: &Path, name: &str, update: bool) -> Result<bool> {
+    let path = dir.join(format!("{name}.png"));
+    if update {
+        actual.write_to_png(&mut File::create(path)?)?;
+        return Ok(true);
+    }
+    let mut reference = match File::open(&path) {
+        Ok(mut file) => ImageSurface::create_from_png(&mut file)?,
+        Err(err) => {
+            println!("{name}: no reference image: {err}");
+            return Ok(false);
+        }
+    };
+    let result = differing_pixels(&mut actual, &mut reference)?;
+    match result {
+        Some(count) if count <= MAX_DIFFERING_PIXELS => return Ok(true),
+        Some(count) => println!("{name}: {count} pixels differ from the reference image"),
+        None => println!("{name}: the size differs from the reference image"),
+    }
+    actual.write_to_png(&mut File::create(dir.join(format!("{name}.actual.png")))?)?;
+    Ok(false)
+}
+
+// Whether FontTemplate matches the font the reference images were rendered with
+fn reference_font_installed(template: &str) -> bool {
+    let fontconfig = FontConfig::new();
+    let mut pattern = Pattern::new(template);
+    fontconfig.perform_substitutions(&mut pattern);
+    match fontconfig.match_pattern(&pattern) {
+        Ok(font) => font.get_file_name().ends_with(REFERENCE_FONT),
+        Err(_) => false
+    }
+}
+
+// A root with the shipped icons, and the directory of the reference images
+// as /etc/tiny-dfr, for the icons only used by the check
+fn icon_root(dir: &Path) -> TempDir {
+    let root = TempDir::new().unwrap();
+    for parent in ["usr/share", "etc"] {
+        create_dir_all(root.path().join(parent)).unwrap();
+    }
+    symlink(concat!(env!("CARGO_MANIFEST_DIR"), "/share/tiny-dfr"), root.path().join("usr/share/tiny-dfr")).unwrap();
+    symlink(dir, root.path().join("etc/tiny-dfr")).unwrap();
+    root
+}
+
+// Renders every layer of the config at the geometries of the real touch bars,
+// with and without outlines and pixel shift, and compares the frames with
+// the reference images next to it, or writes them there when updating.
+#[test]
+fn matches_reference_images() {
+    let dir = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden"));
+    // no other test looks up the files of the daemon
+    let root = icon_root(dir);
+    env::set_var(ROOT_ENV, root.path());
+    let text = read_to_string(dir.join("config.toml")).unwrap();
+    let update = env::var_os(UPDATE_ENV).is_some();
+    let mut failed = Vec::new();
+    let template = text.parse::<toml::Table>().unwrap()["FontTemplate"].as_str().unwrap().to_owned();
+    if !reference_font_installed(&template) {
+        println!("{template} does not match {REFERENCE_FONT}, skipping");
+        return;
+    }
+    for (width, height) in GEOMETRIES {
+        let (mut cfg, mut layers) = parse_config(&text, None, width);
+        for outlines in [false, true] {
+            for shift in pixel_shifts() {
+                cfg.show_button_outlines = outlines;
+                cfg.enable_pixel_shift = shift.is_some();
+                for layer in &mut layers {
+                    let name = format!(
+                        "{}-{width}x{height}-{}-{}",
+                        layer.name,
+                        if outlines { "outlines" } else { "plain" },
+                        match shift {
+                            Some((x, y)) => format!("shift{x}x{y}"),
+                            None => "noshift".into()
+                        }
+                    );
+                    let frame = render(layer, &cfg, width, height, shift).unwrap();
+                    if !check(frame, dir, &name, update).unwrap() {
+                        failed.push(name);
+                    }
+                }
+            }
+        }
+    }
+    assert!(failed.is_empty(), "frames did not match their reference images: {failed:?}");
+}
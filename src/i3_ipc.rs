@@ -0,0 +1,30 @@
+// Framing of the sway and i3 IPC protocol, shared by the daemon and tiny-dfr-ctl,
+// which each only use some of the message types
+#![allow(dead_code)]
+
+pub const MAGIC: &[u8] = b"i3-ipc";
+pub const HEADER_LEN: usize = 14;
+pub const RUN_COMMAND: u32 = 0;
+pub const GET_WORKSPACES: u32 = 1;
+pub const SUBSCRIBE: u32 = 2;
+pub const GET_TREE: u32 = 4;
+// set in the type of event messages
+pub const EVENT_BIT: u32 = 1 << 31;
+
+pub fn encode(msg_type: u32, payload: &str) -> Vec<u8> {
+    let mut msg = MAGIC.to_vec();
+    msg.extend_from_slice(&(payload.len() as u32).to_ne_bytes());
+    msg.extend_from_slice(&msg_type.to_ne_bytes());
+    msg.extend_from_slice(payload.as_bytes());
+    msg
+}
+
+// Returns the type and the payload length of a message, None if it is not one
+pub fn decode_header(header: &[u8; HEADER_LEN]) -> Option<(u32, usize)> {
+    if &header[..6] != MAGIC {
+        return None;
+    }
+    let len = u32::from_ne_bytes(header[6..10].try_into().unwrap()) as usize;
+    let msg_type = u32::from_ne_bytes(header[10..14].try_into().unwrap());
+    Some((msg_type, len))
+}
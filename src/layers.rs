@@ -0,0 +1,178 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+use input::event::keyboard::KeyState;
+use input_linux::Key;
+use serde::Deserialize;
+use crate::FunctionLayer;
+use crate::config::Config;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub enum LayerAction {
+    // Show the layer for as long as the button is held
+    Momentary(String),
+    // Push the layer if it is not on top of the stack, pop it otherwise
+    Toggle(String),
+    Push(String),
+    Pop
+}
+
+impl LayerAction {
+    pub fn target(&self) -> Option<&str> {
+        match self {
+            LayerAction::Momentary(name) | LayerAction::Toggle(name) | LayerAction::Push(name) => Some(name),
+            LayerAction::Pop => None
+        }
+    }
+}
+
+const FN_DOUBLE_TAP_MS: u64 = 300;
+
+pub struct LayerManager {
+    indices: HashMap<String, usize>,
+    default_layer: usize,
+    fn_layer: Option<usize>,
+    app_layers: Vec<(String, usize)>,
+    // replaces default_layer while an app with a layer of its own is focused
+    app_layer: Option<usize>,
+    bindings: Vec<(Key, usize)>,
+    fn_locked: bool,
+    fn_lock_double_tap: bool,
+    fn_lock_chord: Vec<Key>,
+    // chord keys that are currently held
+    chord_held: Vec<Key>,
+    last_fn_press: Option<Instant>,
+    // bound keys that are currently held, in the order they were pressed
+    held_keys: Vec<Key>,
+    // layers shown by currently held momentary buttons, in the order they were pressed
+    momentary: Vec<usize>,
+    stack: Vec<usize>,
+}
+
+impl LayerManager {
+    pub fn new(cfg: &Config, layers: &[FunctionLayer], fn_locked: bool) -> LayerManager {
+        let mut bindings = cfg.modifier_layers.clone();
+        if let Some(fn_layer) = cfg.fn_layer {
+            bindings.push((Key::Fn, fn_layer));
+        }
+        LayerManager {
+            indices: layers.iter().enumerate().map(|(i, l)| (l.name.clone(), i)).collect(),
+            default_layer: cfg.default_layer,
+            fn_layer: cfg.fn_layer,
+            app_layers: cfg.app_layers.clone(),
+            app_layer: None,
+            bindings,
+            fn_locked,
+            fn_lock_double_tap: cfg.fn_lock_double_tap,
+            fn_lock_chord: cfg.fn_lock_chord.clone(),
+            chord_held: Vec::new(),
+            last_fn_press: None,
+            held_keys: Vec::new(),
+            momentary: Vec::new(),
+            stack: Vec::new(),
+        }
+    }
+    pub fn process_key(&mut self, code: u32, state: KeyState) {
+        if self.update_fn_lock(code, state) {
+            return;
+        }
+        let Some(&(key, _)) = self.bindings.iter().find(|(k, _)| *k as u32 == code) else {
+            return;
+        };
+        self.held_keys.retain(|k| *k != key);
+        if state == KeyState::Pressed {
+            self.held_keys.push(key);
+        }
+    }
+    // Returns true if the key press toggled Fn-lock and should not be processed further
+    fn update_fn_lock(&mut self, code: u32, state: KeyState) -> bool {
+        if let Some(&key) = self.fn_lock_chord.iter().find(|k| **k as u32 == code) {
+            self.chord_held.retain(|k| *k != key);
+            if state == KeyState::Pressed {
+                self.chord_held.push(key);
+                if self.chord_held.len() == self.fn_lock_chord.len() {
+                    self.fn_locked = !self.fn_locked;
+                    return true;
+                }
+            }
+        }
+        if !self.fn_lock_double_tap || state != KeyState::Pressed {
+            return false;
+        }
+        if code != Key::Fn as u32 {
+            // Fn used as a modifier for another key is not a tap
+            self.last_fn_press = None;
+            return false;
+        }
+        let now = Instant::now();
+        match self.last_fn_press {
+            Some(last) if now - last < Duration::from_millis(FN_DOUBLE_TAP_MS) => {
+                self.fn_locked = !self.fn_locked;
+                self.last_fn_press = None;
+                true
+            },
+            _ => {
+                self.last_fn_press = Some(now);
+                false
+            }
+        }
+    }
+    pub fn index(&self, name: &str) -> Option<usize> {
+        self.indices.get(name).copied()
+    }
+    // Replaces the layer stack with the given layer
+    pub fn show(&mut self, layer: usize) {
+        self.stack = vec![layer];
+    }
+    // An empty app id means nothing is focused
+    pub fn set_focused_app(&mut self, app: &str) {
+        let app = app.to_lowercase();
+        self.app_layer = self.app_layers.iter().find(|(a, _)| *a == app).map(|(_, layer)| *layer);
+    }
+    pub fn fn_locked(&self) -> bool {
+        self.fn_locked
+    }
+    pub fn apply(&mut self, action: &LayerAction, pressed: bool) {
+        let target = action.target().map(|name| self.indices[name]);
+        match (action, target) {
+            (LayerAction::Momentary(_), Some(layer)) => {
+                if pressed {
+                    self.momentary.push(layer);
+                } else if let Some(pos) = self.momentary.iter().rposition(|l| *l == layer) {
+                    self.momentary.remove(pos);
+                }
+            },
+            (LayerAction::Toggle(_), Some(layer)) if pressed => {
+                if self.stack.last() == Some(&layer) {
+                    self.stack.pop();
+                } else {
+                    self.stack.push(layer);
+                }
+            },
+            (LayerAction::Push(_), Some(layer)) if pressed => {
+                self.stack.push(layer);
+            },
+            (LayerAction::Pop, _) if pressed => {
+                self.stack.pop();
+            },
+            _ => {}
+        }
+    }
+    pub fn active(&self) -> usize {
+        if let Some(&layer) = self.momentary.last() {
+            return layer;
+        }
+        let default_layer = self.app_layer.unwrap_or(self.default_layer);
+        let fn_layer = self.fn_layer.filter(|_| self.fn_locked);
+        let held = self.held_keys.last().and_then(|key| {
+            // with Fn-lock on, holding Fn goes back to the default layer
+            if *key == Key::Fn && fn_layer.is_some() {
+                return Some(default_layer);
+            }
+            self.bindings.iter().find(|(k, _)| k == key).map(|(_, layer)| *layer)
+        });
+        held.or(self.stack.last().copied()).or(fn_layer).unwrap_or(default_layer)
+    }
+}
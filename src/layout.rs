@@ -0,0 +1,206 @@
+use drm::control::ClipRect;
+use crate::config::Theme;
+use crate::pixel_shift::PIXEL_SHIFT_WIDTH_PX;
+
+// In pixels along and across the bar, the way it is seen
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Rect {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl Rect {
+    pub fn contains(&self, x: f64, y: f64) -> bool {
+        x >= self.x && x <= self.x + self.width && y >= self.y && y <= self.y + self.height
+    }
+}
+
+// Where the buttons of a layer are, for both drawing them and telling which
+// one a touch is on, so that the two never disagree
+#[derive(Clone, Copy, Debug)]
+pub struct Layout {
+    pub width: u16,
+    pub height: u16,
+    // kept free at both ends of the bar, for the pixel shift to move the buttons into
+    margin: f64,
+    shift: (f64, f64),
+    spacing: f64,
+    pub radius: f64,
+    inset: f64,
+}
+
+impl Layout {
+    pub fn new(width: u16, height: u16, pixel_shift: Option<(f64, f64)>, theme: &Theme) -> Layout {
+        let margin = if pixel_shift.is_some() { (PIXEL_SHIFT_WIDTH_PX / 2) as f64 } else { 0.0 };
+        Layout {
+            width,
+            height,
+            margin,
+            shift: pixel_shift.unwrap_or((0.0, 0.0)),
+            spacing: theme.button_spacing,
+            radius: theme.button_radius,
+            inset: theme.button_inset,
+        }
+    }
+    // How far the content of the buttons is moved across the bar
+    pub fn shift_y(&self) -> f64 {
+        self.shift.1
+    }
+    fn virtual_button_width(&self, count: usize) -> f64 {
+        (self.width as f64 - 2.0 * self.margin - self.spacing * (count - 1) as f64) / count as f64
+    }
+    // The button that takes up the virtual buttons from start up to end, of count in total
+    // Where the virtual button i starts, relative to the first one
+    fn virtual_button_x(&self, i: usize, count: usize) -> f64 {
+        (i as f64 * (self.virtual_button_width(count) + self.spacing)).floor()
+    }
+    pub fn button(&self, start: usize, end: usize, count: usize) -> Rect {
+        let virtual_button_width = self.virtual_button_width(count);
+        let x = self.virtual_button_x(start, count);
+        let width = virtual_button_width + ((end - start - 1) as f64 * (virtual_button_width + self.spacing)).floor();
+        // rounding up would reach into the next button when there is little spacing
+        let width = width.ceil().min(self.virtual_button_x(end, count) - x);
+        let y = self.height as f64 * self.inset - self.radius;
+        Rect {
+            x: x + self.shift.0 + self.margin,
+            y,
+            width,
+            height: self.height as f64 - 2.0 * y,
+        }
+    }
+    // The part of the frame buffer, which is rotated, that a rect drawn on it
+    // covers, cut to the bar as large radii reach outside of it
+    pub fn clip_rect(&self, rect: &Rect) -> ClipRect {
+        let (width, height) = (self.width as f64, self.height as f64);
+        ClipRect::new(
+            (height - rect.y - rect.height).clamp(0.0, height) as u16,
+            rect.x.clamp(0.0, width) as u16,
+            (height - rect.y).clamp(0.0, height) as u16,
+            (rect.x + rect.width).clamp(0.0, width) as u16,
+        )
+    }
+    // The last virtual button starting at or before x, of count in total,
+    // which is the one x is on if it is on any
+    pub fn virtual_index(&self, x: f64, count: usize) -> usize {
+        let x = x - self.shift.0 - self.margin;
+        let i = (x / (self.virtual_button_width(count) + self.spacing)).max(0.0) as usize;
+        // the buttons start at whole pixels, up to one before where they would otherwise
+        if i + 1 < count && x >= self.virtual_button_x(i + 1, count) {
+            return i + 1;
+        }
+        i.min(count - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use input_linux::Key;
+    use proptest::prelude::*;
+    use crate::config::ButtonConfig;
+    use crate::macros::KeyChord;
+    use crate::pixel_shift::PIXEL_SHIFT_WIDTH_PX;
+    use crate::FunctionLayer;
+    use super::{Layout, Rect};
+
+    const HEIGHT: u16 = 60;
+
+    // As Layout::new builds it from a theme
+    fn layout(width: u16, spacing: f64, shift: Option<(f64, f64)>, radius: f64, inset: f64) -> Layout {
+        Layout {
+            width,
+            height: HEIGHT,
+            margin: if shift.is_some() { (PIXEL_SHIFT_WIDTH_PX / 2) as f64 } else { 0.0 },
+            shift: shift.unwrap_or((0.0, 0.0)),
+            spacing,
+            radius,
+            inset,
+        }
+    }
+
+    // The first virtual button of each button, and the number of virtual buttons
+    fn starts(stretches: &[usize]) -> (Vec<usize>, usize) {
+        let starts = stretches.iter().scan(0, |start, stretch| {
+            *start += stretch;
+            Some(*start - stretch)
+        }).collect();
+        (starts, stretches.iter().sum())
+    }
+
+    fn rects(layout: &Layout, stretches: &[usize]) -> Vec<Rect> {
+        let (starts, count) = starts(stretches);
+        (0..starts.len()).map(|i| {
+            layout.button(starts[i], starts.get(i + 1).copied().unwrap_or(count), count)
+        }).collect()
+    }
+
+    // Random bars, with buttons at least a pixel wide
+    fn bars() -> impl Strategy<Value = (Layout, Vec<usize>)> {
+        let max_shift = (PIXEL_SHIFT_WIDTH_PX / 2) as f64;
+        (
+            500u16..2600,
+            0.0..40.0,
+            proptest::option::of((-max_shift..=max_shift, -1.0..=1.0)),
+            proptest::collection::vec(1usize..=4, 1..=24),
+            0.0..40.0,
+            0.0..0.5,
+        ).prop_map(|(width, spacing, shift, stretches, radius, inset)| (layout(width, spacing, shift, radius, inset), stretches))
+            .prop_filter("buttons narrower than a pixel", |(layout, stretches)| {
+                layout.virtual_button_width(stretches.iter().sum()) >= 1.0
+            })
+    }
+
+    proptest! {
+        #[test]
+        fn drawn_buttons_are_found_again((layout, stretches) in bars(), fraction in 0.001..0.999) {
+            let (starts, count) = starts(&stretches);
+            for (i, rect) in rects(&layout, &stretches).into_iter().enumerate() {
+                // not the edges, which the next button can share when there is no spacing,
+                // and where undoing the pixel shift can round either way
+                for fraction in [fraction, 0.5] {
+                    let x = rect.x + fraction * rect.width;
+                    let virtual_i = layout.virtual_index(x, count);
+                    let button = starts.iter().rposition(|start| *start <= virtual_i).unwrap();
+                    prop_assert_eq!(button, i, "x = {} in {:?}", x, rect);
+                }
+            }
+        }
+
+        #[test]
+        fn buttons_do_not_overlap((layout, stretches) in bars()) {
+            let rects = rects(&layout, &stretches);
+            for pair in rects.windows(2) {
+                // buttons can touch without spacing, where adding the pixel shift rounds either way
+                prop_assert!(pair[0].x + pair[0].width <= pair[1].x + 1e-9, "{:?} overlaps {:?}", pair[0], pair[1]);
+            }
+        }
+
+        #[test]
+        fn hit_matches_button_rect((layout, stretches) in bars(), x in 0.0..1.0, y in 0.0..1.0) {
+            let cfg = stretches.iter().map(|stretch| ButtonConfig {
+                text: Some("F1".into()),
+                action: Some(KeyChord::Single(Key::F1)),
+                stretch: Some(*stretch),
+                ..Default::default()
+            }).collect();
+            let layer = FunctionLayer::with_config("test".into(), cfg);
+            let (x, y) = (x * layout.width as f64, y * layout.height as f64);
+            let drawn = (0..stretches.len()).find(|i| layer.button_rect(&layout, *i).contains(x, y));
+            prop_assert_eq!(layer.hit(&layout, x, y, None), drawn);
+            for i in 0..stretches.len() {
+                let contains = layer.button_rect(&layout, i).contains(x, y);
+                prop_assert_eq!(layer.hit(&layout, x, y, Some(i)), contains.then_some(i));
+            }
+        }
+
+        #[test]
+        fn clip_rects_are_on_the_bar((layout, stretches) in bars()) {
+            for rect in rects(&layout, &stretches) {
+                let clip = layout.clip_rect(&rect);
+                prop_assert!(clip.x1() <= clip.x2() && clip.x2() <= layout.height, "{:?} for {:?}", clip, rect);
+                prop_assert!(clip.y1() <= clip.y2() && clip.y2() <= layout.width, "{:?} for {:?}", clip, rect);
+            }
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use std::{
+    cmp::min,
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+use input_linux::Key;
+use serde::Deserialize;
+use crate::toggle_key;
+use crate::uinput::KeySink;
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum KeyChord {
+    Single(Key),
+    Chord(Vec<Key>)
+}
+
+impl KeyChord {
+    pub fn keys(&self) -> &[Key] {
+        match self {
+            KeyChord::Single(key) => std::slice::from_ref(key),
+            KeyChord::Chord(keys) => keys
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub enum MacroStep {
+    // Press the keys in order, then release them in reverse order
+    Keys(KeyChord),
+    // Wait for the given number of milliseconds before the next step
+    Delay(u64)
+}
+
+// Keys are pressed in the order they are listed and released in reverse,
+// so that modifiers wrap the keys they apply to.
+pub fn press_chord(uinput: &mut dyn KeySink, keys: &[Key], pressed: bool) {
+    if pressed {
+        for key in keys {
+            toggle_key(uinput, *key, 1);
+        }
+    } else {
+        for key in keys.iter().rev() {
+            toggle_key(uinput, *key, 0);
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "PascalCase")]
+pub struct RepeatConfig {
+    // milliseconds from the press to the first repeat
+    pub delay: u64,
+    // repeats per second
+    pub rate: u64,
+}
+
+struct Repeating {
+    key: Key,
+    next_at: Instant,
+    interval: Duration,
+}
+
+enum PendingStep {
+    Keys(Vec<Key>),
+    Delay(Duration)
+}
+
+// Plays macros and repeats held keys without blocking the main loop,
+// delays are waited out through the epoll timeout.
+pub struct MacroPlayer {
+    queue: VecDeque<PendingStep>,
+    resume_at: Instant,
+    // only the most recently pressed key repeats, like on a keyboard
+    repeating: Option<Repeating>,
+}
+
+impl MacroPlayer {
+    pub fn new() -> MacroPlayer {
+        MacroPlayer {
+            queue: VecDeque::new(),
+            resume_at: Instant::now(),
+            repeating: None,
+        }
+    }
+    pub fn play(&mut self, steps: &[MacroStep]) {
+        self.queue.extend(steps.iter().map(|step| match step {
+            MacroStep::Keys(chord) => PendingStep::Keys(chord.keys().to_vec()),
+            MacroStep::Delay(ms) => PendingStep::Delay(Duration::from_millis(*ms)),
+        }));
+    }
+    // The key is repeated by releasing and pressing it again, as repeat
+    // events sent by the kernel are ignored by libinput
+    pub fn start_repeat(&mut self, key: Key, cfg: &RepeatConfig) {
+        self.repeating = Some(Repeating {
+            key,
+            next_at: Instant::now() + Duration::from_millis(cfg.delay),
+            interval: Duration::from_millis(1000 / cfg.rate),
+        });
+    }
+    pub fn stop_repeat(&mut self, key: Key) {
+        if self.repeating.as_ref().is_some_and(|r| r.key == key) {
+            self.repeating = None;
+        }
+    }
+    // Returns how long until it needs to be called again
+    pub fn update(&mut self, uinput: &mut dyn KeySink) -> i32 {
+        min(self.update_macros(uinput), self.update_repeat(uinput))
+    }
+    fn update_repeat(&mut self, uinput: &mut dyn KeySink) -> i32 {
+        let Some(repeating) = self.repeating.as_mut() else {
+            return i32::MAX;
+        };
+        let now = Instant::now();
+        if now >= repeating.next_at {
+            toggle_key(uinput, repeating.key, 0);
+            toggle_key(uinput, repeating.key, 1);
+            // repeats missed while the main loop was busy are dropped rather than sent in a burst
+            repeating.next_at = now + repeating.interval;
+        }
+        (repeating.next_at - now).as_millis() as i32 + 1
+    }
+    fn update_macros(&mut self, uinput: &mut dyn KeySink) -> i32 {
+        loop {
+            let now = Instant::now();
+            if now < self.resume_at {
+                return (self.resume_at - now).as_millis() as i32 + 1;
+            }
+            match self.queue.pop_front() {
+                Some(PendingStep::Keys(keys)) => {
+                    press_chord(uinput, &keys, true);
+                    press_chord(uinput, &keys, false);
+                },
+                Some(PendingStep::Delay(delay)) => {
+                    self.resume_at = now + delay;
+                },
+                None => return i32::MAX
+            }
+        }
+    }
+}
@@ -1,29 +1,20 @@
 use std::{
-    fs::{File, OpenOptions},
-    os::{
-        fd::{AsRawFd, AsFd},
-        unix::{io::OwnedFd, fs::OpenOptionsExt}
-    },
+    fs::File,
+    io::{Cursor, Read},
     path::{Path, PathBuf},
     collections::HashMap,
     cmp::min,
-    panic::{self, AssertUnwindSafe}
+    panic::{self, AssertUnwindSafe},
+    iter, mem, process,
+    time::{Duration, Instant},
 };
 use cairo::{ImageSurface, Format, Context, Surface, Rectangle, Antialias};
 use rsvg::{Loader, CairoRenderer, SvgHandle};
+use gio::{glib, MemoryInputStream};
 use drm::control::ClipRect;
 use anyhow::{anyhow, Result};
-use input::{
-    Libinput, LibinputInterface, Device as InputDevice,
-    event::{
-        Event, device::DeviceEvent, EventTrait,
-        touch::{TouchEvent, TouchEventPosition, TouchEventSlot},
-        keyboard::{KeyboardEvent, KeyboardEventTrait, KeyState}
-    }
-};
-use libc::{O_ACCMODE, O_RDONLY, O_RDWR, O_WRONLY, c_char};
-use input_linux::{uinput::UInputHandle, EventKind, Key, SynchronizeKind};
-use input_linux_sys::{uinput_setup, input_id, timeval, input_event};
+use input::event::keyboard::KeyState;
+use input_linux::{EventKind, Key, SynchronizeKind};
 use nix::{
     sys::{
         signal::{Signal, SigSet},
@@ -33,24 +24,70 @@ use nix::{
 };
 use privdrop::PrivDrop;
 use freedesktop_icons::lookup;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 
 mod backlight;
 mod display;
+mod offscreen;
 mod pixel_shift;
 mod fonts;
 mod config;
+mod layers;
+mod state;
+mod macros;
+mod commands;
+mod control;
+mod dbus;
+mod widgets;
+mod sysfs;
+mod slider;
+mod gestures;
+mod modifiers;
+mod workspaces;
+mod i3_ipc;
+mod media;
+mod layout;
+mod events;
+mod uinput;
+#[cfg(test)]
+mod test_bus;
+#[cfg(test)]
+mod golden;
 
 use backlight::BacklightManager;
-use display::DrmBackend;
-use pixel_shift::{PixelShiftManager, PIXEL_SHIFT_WIDTH_PX};
-use config::{ButtonConfig, Config};
+use display::{DisplayBackend, DrmBackend};
+use offscreen::{OffscreenBackend, OffscreenTarget};
+use pixel_shift::PixelShiftManager;
+use config::{system_path, ActionConfig, ButtonConfig, Color, Config, StateConfig};
 use crate::config::ConfigManager;
+use layers::{LayerAction, LayerManager};
+use state::{State, StateManager};
+use macros::{MacroPlayer, MacroStep, RepeatConfig, press_chord};
+use commands::{CommandConfig, CommandRunner, COMMAND_RUNNER_ARG};
+use control::{ControlServer, LayerStatus, Request, Status};
+use dbus::DbusService;
+use widgets::{Widget, WidgetContent, WidgetKind, WidgetSources};
+use sysfs::{BatteryMonitor, UeventMonitor};
+use slider::Slider;
+use gestures::{swipe, Gesture, GestureEvent, GestureOptions, GestureRecognizer};
+use modifiers::{Modifier, ModifierState};
+use workspaces::Workspaces;
+use media::{MediaCommand, MediaMonitor};
+use layout::{Layout, Rect};
+use events::{InputEvent, InputSource, LibinputSource, ScriptedSource};
+use uinput::{KeyRecorder, KeySink};
 
-const BUTTON_SPACING_PX: i32 = 16;
-const BUTTON_COLOR_INACTIVE: f64 = 0.200;
-const BUTTON_COLOR_ACTIVE: f64 = 0.400;
+// the size icons are loaded at, they are scaled to the one of the theme when drawn
 const ICON_SIZE: i32 = 48;
 const TIMEOUT_MS: i32 = 10 * 1000;
+const DEFAULT_HOLD_TIME_MS: u64 = 500;
+// the touch bar of the 13 inch MacBook Pros
+const OFFSCREEN_DEFAULT_SIZE: (u16, u16) = (2008, 60);
+// widget text that does not fit in its button scrolls by at this speed
+const SCROLL_SPEED_PX: f64 = 40.0;
+const SCROLL_GAP_PX: f64 = 48.0;
+const SCROLL_FRAME_MS: i32 = 50;
+const WIDGET_MARGIN_PX: f64 = 8.0;
 
 enum ButtonImage {
     Text(String),
@@ -58,11 +95,126 @@ enum ButtonImage {
     Bitmap(ImageSurface)
 }
 
+enum ButtonAction {
+    Keys(Vec<Key>, Option<RepeatConfig>),
+    Macro(Vec<MacroStep>),
+    Layer(LayerAction),
+    Command(CommandConfig),
+    Slider(Slider),
+    // switches to the workspace at this position
+    Workspace(usize),
+    Media(MediaCommand),
+    None
+}
+
+impl ButtonAction {
+    // Returns None if no action is set
+    fn with_config(cfg: ActionConfig) -> Option<ButtonAction> {
+        match (cfg.action, cfg.macro_steps, cfg.layer, cfg.command, cfg.media) {
+            (Some(chord), None, None, None, None) => Some(ButtonAction::Keys(chord.keys().to_vec(), None)),
+            (None, Some(steps), None, None, None) => Some(ButtonAction::Macro(steps)),
+            (None, None, Some(layer), None, None) => Some(ButtonAction::Layer(layer)),
+            (None, None, None, Some(command), None) => Some(ButtonAction::Command(command.into())),
+            (None, None, None, None, Some(command)) => Some(ButtonAction::Media(command)),
+            (None, None, None, None, None) => None,
+            _ => panic!("Invalid config, an action must have only one of Action, Macro, Layer, Command or Media")
+        }
+    }
+    fn keys(&self) -> Vec<Key> {
+        match self {
+            ButtonAction::Keys(keys, _) => keys.clone(),
+            ButtonAction::Macro(steps) => steps.iter().flat_map(|step| match step {
+                MacroStep::Keys(chord) => chord.keys().to_vec(),
+                MacroStep::Delay(_) => Vec::new()
+            }).collect(),
+            ButtonAction::Slider(slider) => slider.keys(),
+            ButtonAction::Layer(_) | ButtonAction::Command(_) | ButtonAction::Workspace(_) | ButtonAction::Media(_) | ButtonAction::None => Vec::new()
+        }
+    }
+    fn apply(&self, ctx: &mut ActionContext, active: bool) {
+        match self {
+            ButtonAction::Keys(keys, repeat) => {
+                press_chord(ctx.uinput.as_mut(), keys, active);
+                // only the last key of a chord repeats, the others are usually modifiers
+                if let (Some(repeat), Some(key)) = (repeat, keys.last()) {
+                    if active {
+                        ctx.macro_player.start_repeat(*key, repeat);
+                    } else {
+                        ctx.macro_player.stop_repeat(*key);
+                    }
+                }
+            },
+            ButtonAction::Macro(steps) => if active {
+                ctx.macro_player.play(steps);
+            },
+            ButtonAction::Layer(action) => ctx.layer_mgr.apply(action, active),
+            ButtonAction::Command(cmd) => if active {
+                ctx.cmd_runner.run(cmd);
+            },
+            ButtonAction::Workspace(index) => if active {
+                ctx.session.workspaces.switch(*index);
+            },
+            ButtonAction::Media(command) => if active {
+                ctx.session.media.send(*command);
+            },
+            // sliders act on where they are touched, see FunctionLayer::slide
+            ButtonAction::Slider(_) | ButtonAction::None => {}
+        }
+    }
+}
+
+// Connections into the user session, handed over by tiny-dfr-ctl
+struct Session {
+    workspaces: Workspaces,
+    media: MediaMonitor,
+}
+
+// Everything the actions of buttons act on
+struct ActionContext {
+    uinput: Box<dyn KeySink>,
+    layer_mgr: LayerManager,
+    macro_player: MacroPlayer,
+    cmd_runner: CommandRunner,
+    session: Session,
+}
+
 struct Button {
+    label: String,
     image: ButtonImage,
+    color: Option<Color>,
+    widget: Option<(Widget, WidgetContent)>,
+    // album art shown next to a widget's text
+    thumbnail: Option<ButtonImage>,
+    // when the text last changed, and whether it is too long to fit, for scrolling it
+    scroll_start: Instant,
+    overflowing: bool,
+    // shown instead of the whole bar when the button is held for hold_time
+    on_hold: Option<FunctionLayer>,
+    hold_time: Duration,
+    gestures: Vec<(Gesture, ButtonAction)>,
+    toggle: Option<Toggle>,
+    variants: Vec<Variant>,
+    // the variant shown for the modifiers that are held, and the one the button was pressed with
+    variant: Option<usize>,
+    pressed_variant: Option<usize>,
     changed: bool,
     active: bool,
-    action: Key,
+    action: ButtonAction,
+}
+
+// The look and action of the state of a toggle button that is not shown,
+// they are swapped with the button's own when it flips
+struct Toggle {
+    on: bool,
+    other_look: Option<(ButtonImage, String)>,
+    other_action: Option<ButtonAction>,
+}
+
+// The look and action of a button while exactly these modifiers are held
+struct Variant {
+    modifiers: Vec<Modifier>,
+    look: Option<(ButtonImage, String)>,
+    action: Option<ButtonAction>,
 }
 
 fn try_load_svg(path: impl AsRef<Path>) -> Result<ButtonImage> {
@@ -72,7 +224,11 @@ fn try_load_svg(path: impl AsRef<Path>) -> Result<ButtonImage> {
 
 fn try_load_png(path: impl AsRef<Path>) -> Result<ButtonImage> {
     let mut file = File::open(path)?;
-    let surf = ImageSurface::create_from_png(&mut file)?;
+    load_png(&mut file)
+}
+
+fn load_png(reader: &mut impl Read) -> Result<ButtonImage> {
+    let surf = ImageSurface::create_from_png(reader)?;
     if surf.height() == ICON_SIZE && surf.width() == ICON_SIZE {
         return Ok(ButtonImage::Bitmap(surf));
     }
@@ -85,9 +241,51 @@ fn try_load_png(path: impl AsRef<Path>) -> Result<ButtonImage> {
     return Ok(ButtonImage::Bitmap(resized));
 }
 
+// Loads an icon sent by a client, which can be either a png or an svg
+fn load_image_data(data: Vec<u8>) -> Result<ButtonImage> {
+    if data.starts_with(b"\x89PNG") {
+        return load_png(&mut Cursor::new(data));
+    }
+    let stream = MemoryInputStream::from_bytes(&glib::Bytes::from_owned(data));
+    let handle = Loader::new().read_stream(&stream, None::<&gio::File>, None::<&gio::Cancellable>)?;
+    Ok(ButtonImage::Svg(handle))
+}
+
+// Album art is read by tiny-dfr-ctl, in the user session, and sent over the control socket
+fn load_art(data: &[u8]) -> Result<ButtonImage> {
+    if data.starts_with(b"\x89PNG") {
+        return load_png(&mut Cursor::new(data));
+    }
+    // librsvg decodes the other formats players use when they are embedded in an svg
+    let mime = if data.starts_with(b"\xff\xd8") {
+        "image/jpeg"
+    } else if data.starts_with(b"GIF8") {
+        "image/gif"
+    } else if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+        "image/webp"
+    } else {
+        return Err(anyhow!("unsupported art format"));
+    };
+    let svg = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{ICON_SIZE}" height="{ICON_SIZE}"><image width="{ICON_SIZE}" height="{ICON_SIZE}" href="data:{mime};base64,{}"/></svg>"#,
+        BASE64.encode(data)
+    );
+    let ButtonImage::Svg(handle) = load_image_data(svg.into_bytes())? else {
+        unreachable!()
+    };
+    // rendered once here instead of on every redraw
+    let surf = ImageSurface::create(Format::ARgb32, ICON_SIZE, ICON_SIZE)?;
+    let c = Context::new(&surf)?;
+    CairoRenderer::new(&handle).render_document(&c, &Rectangle::new(0.0, 0.0, ICON_SIZE as f64, ICON_SIZE as f64))?;
+    drop(c);
+    Ok(ButtonImage::Bitmap(surf))
+}
+
+const ICON_DIRS: [&str; 2] = ["/etc/tiny-dfr", "/usr/share/tiny-dfr"];
+
 fn try_load_image(name: impl AsRef<str>, theme: Option<impl AsRef<str>>) -> Result<ButtonImage> {
     let name = name.as_ref();
-    let locations;
+    let locations: Vec<PathBuf>;
 
     // Load list of candidate locations
     if let Some(theme) = theme {
@@ -104,12 +302,10 @@ fn try_load_image(name: impl AsRef<str>, theme: Option<impl AsRef<str>>) -> Resu
         locations = candidates.into_iter().flatten().collect();
     } else {
         // Standard file icons
-        locations = vec![
-            PathBuf::from(format!("/etc/tiny-dfr/{name}.svg")),
-            PathBuf::from(format!("/etc/tiny-dfr/{name}.png")),
-            PathBuf::from(format!("/usr/share/tiny-dfr/{name}.svg")),
-            PathBuf::from(format!("/usr/share/tiny-dfr/{name}.png")),
-        ];
+        locations = ICON_DIRS.iter().map(|dir| system_path(dir)).flat_map(|dir| [
+            dir.join(format!("{name}.svg")),
+            dir.join(format!("{name}.png")),
+        ]).collect();
     };
 
     // Try to load each candidate
@@ -134,84 +330,342 @@ fn try_load_image(name: impl AsRef<str>, theme: Option<impl AsRef<str>>) -> Resu
     Err(last_err.context(format!("failed loading all possible paths for icon {name}")))
 }
 
+// Loads the look and action of a button state, each is None if not set
+fn load_state(cfg: StateConfig) -> (Option<(ButtonImage, String)>, Option<ButtonAction>) {
+    let look = if let Some(text) = cfg.text {
+        Some((ButtonImage::Text(text.clone()), text))
+    } else {
+        cfg.icon.map(|icon| (try_load_image(&icon, cfg.theme).expect("failed to load icon"), icon))
+    };
+    (look, ButtonAction::with_config(cfg.action))
+}
+
 impl Button {
     fn with_config(cfg: ButtonConfig) -> Button {
-        if let Some(text) = cfg.text {
-            Button::new_text(text, cfg.action)
+        let gestures = [
+            (Gesture::DoubleTap, cfg.double_tap),
+            (Gesture::LongPress, cfg.long_press),
+            (Gesture::SwipeLeft, cfg.swipe_left),
+            (Gesture::SwipeRight, cfg.swipe_right),
+        ].into_iter().filter_map(|(gesture, action_cfg)| {
+            let action = ButtonAction::with_config(action_cfg?)
+                .unwrap_or_else(|| panic!("Invalid config, {gesture:?} must have one of Action, Macro, Layer, Command or Media"));
+            Some((gesture, action))
+        }).collect::<Vec<_>>();
+        if cfg.on_hold.is_some() && gestures.iter().any(|(gesture, _)| *gesture == Gesture::LongPress) {
+            panic!("Invalid config, a button cannot have both OnHold and LongPress");
+        }
+        let action = ButtonAction::with_config(ActionConfig {
+            action: cfg.action,
+            macro_steps: cfg.macro_steps,
+            layer: cfg.layer,
+            command: cfg.command,
+            media: cfg.media,
+        });
+        let action = match (action, cfg.slider) {
+            (Some(action), None) => action,
+            (None, Some(slider)) => ButtonAction::Slider(Slider::with_config(slider)),
+            // a workspace button switches to its workspace unless given another action
+            (None, None) if matches!(cfg.widget, Some(WidgetKind::Workspace)) => {
+                ButtonAction::Workspace(cfg.workspace.unwrap_or(1).saturating_sub(1))
+            },
+            (None, None) if matches!(cfg.widget, Some(WidgetKind::Media)) => ButtonAction::Media(MediaCommand::PlayPause),
+            (None, None) if cfg.widget.is_some() || !gestures.is_empty() => ButtonAction::None,
+            _ => panic!("Invalid config, a button must have exactly one of Action, Macro, Layer, Command, Media or Slider")
+        };
+        let action = match (action, cfg.repeat) {
+            (action, None) => action,
+            // the interval is counted in whole milliseconds
+            (ButtonAction::Keys(keys, _), Some(repeat)) if (1..=1000).contains(&repeat.rate) => ButtonAction::Keys(keys, Some(repeat)),
+            (ButtonAction::Keys(..), Some(_)) => panic!("Invalid config, Repeat must have a Rate from 1 to 1000"),
+            _ => panic!("Invalid config, Repeat can only be used with Action")
+        };
+        let mut button = if let Some(kind) = cfg.widget {
+            // the content is filled in by the first update_widgets call
+            let mut button = Button::new_text(String::new(), action);
+            button.widget = Some((Widget::new(kind, cfg.format, cfg.workspace), WidgetContent::default()));
+            button
+        } else if cfg.workspace.is_some() {
+            panic!("Invalid config, Workspace can only be used with the workspace widget")
+        } else if let Some(text) = cfg.text {
+            Button::new_text(text, action)
         } else if let Some(icon) = cfg.icon {
-            Button::new_icon(&icon, cfg.theme, cfg.action)
+            Button::new_icon(&icon, cfg.theme, action)
         } else {
             panic!("Invalid config, a button must have either Text or Icon")
-        }
+        };
+        button.on_hold = cfg.on_hold.map(|hold_cfg| FunctionLayer::with_config("hold".into(), vec![*hold_cfg]));
+        button.hold_time = Duration::from_millis(cfg.hold_time.unwrap_or(DEFAULT_HOLD_TIME_MS));
+        button.gestures = gestures;
+        button.toggle = match (cfg.toggle.unwrap_or(false), cfg.on) {
+            (false, None) => None,
+            (false, Some(_)) => panic!("Invalid config, On can only be used with Toggle = true"),
+            (true, _) if matches!(button.action, ButtonAction::Slider(_)) => panic!("Invalid config, a slider cannot be a toggle"),
+            (true, on) => {
+                let (other_look, other_action) = load_state(on.unwrap_or_default());
+                Some(Toggle { on: false, other_look, other_action })
+            }
+        };
+        button.variants = cfg.variants.unwrap_or_default().into_iter().map(|variant| {
+            let mut modifiers = variant.modifiers;
+            modifiers.sort();
+            modifiers.dedup();
+            let (look, action) = load_state(variant.state);
+            Variant { modifiers, look, action }
+        }).collect();
+        button
     }
-    fn new_text(text: String, action: Key) -> Button {
+    fn new_text(text: String, action: ButtonAction) -> Button {
         Button {
             action,
             active: false,
             changed: false,
+            label: text.clone(),
             image: ButtonImage::Text(text),
+            color: None,
+            widget: None,
+            thumbnail: None,
+            scroll_start: Instant::now(),
+            overflowing: false,
+            on_hold: None,
+            hold_time: Duration::ZERO,
+            gestures: Vec::new(),
+            toggle: None,
+            variants: Vec::new(),
+            variant: None,
+            pressed_variant: None,
         }
     }
-    fn new_icon(path: impl AsRef<str>, theme: Option<impl AsRef<str>>, action: Key) -> Button {
+    fn new_icon(path: impl AsRef<str>, theme: Option<impl AsRef<str>>, action: ButtonAction) -> Button {
+        let label = path.as_ref().to_string();
         let image = try_load_image(path, theme).expect("failed to load icon");
         Button {
-            action, image,
+            action, image, label,
+            color: None,
+            widget: None,
+            thumbnail: None,
+            scroll_start: Instant::now(),
+            overflowing: false,
+            on_hold: None,
+            hold_time: Duration::ZERO,
+            gestures: Vec::new(),
+            toggle: None,
+            variants: Vec::new(),
+            variant: None,
+            pressed_variant: None,
             active: false,
             changed: false,
         }
     }
-    fn render(&self, c: &Context, height: i32, button_left_edge: f64, button_width: u64, y_shift: f64) {
-        match &self.image {
+    fn actions(&self) -> impl Iterator<Item = &ButtonAction> {
+        let toggle_action = self.toggle.as_ref().and_then(|toggle| toggle.other_action.as_ref());
+        iter::once(&self.action)
+            .chain(toggle_action)
+            .chain(self.variants.iter().filter_map(|variant| variant.action.as_ref()))
+            .chain(self.gestures.iter().map(|(_, action)| action))
+    }
+    fn gesture_action(&self, gesture: Gesture) -> Option<&ButtonAction> {
+        self.gestures.iter().find(|(g, _)| *g == gesture).map(|(_, action)| action)
+    }
+    // Buttons without gestures of their own are pressed as soon as they are touched,
+    // swiping across them is only recognized once they are pressed, see Touch::Button
+    fn gesture_options(&self) -> Option<GestureOptions> {
+        if matches!(self.action, ButtonAction::Slider(_)) || (self.gestures.is_empty() && self.on_hold.is_none()) {
+            return None;
+        }
+        let long_press = self.on_hold.is_some() || self.gesture_action(Gesture::LongPress).is_some();
+        Some(GestureOptions {
+            long_press: if long_press { Some(self.hold_time) } else { None },
+            double_tap: self.gesture_action(Gesture::DoubleTap).is_some(),
+        })
+    }
+    fn keys(&self) -> Vec<Key> {
+        let mut keys = self.actions().flat_map(|action| action.keys()).collect::<Vec<_>>();
+        if let Some(layer) = &self.on_hold {
+            keys.extend(layer.buttons.iter().flat_map(|(_, button)| button.keys()));
+        }
+        keys
+    }
+    // Returns how long until the widget, if any, needs to be updated again
+    fn update_widget(&mut self, sources: &WidgetSources) -> i32 {
+        let hold_next_update_ms = self.on_hold.as_mut().map_or(i32::MAX, |layer| layer.update_widgets(sources));
+        let Some((widget, content)) = self.widget.as_mut() else {
+            return hold_next_update_ms;
+        };
+        let (new_content, next_update_ms) = widget.update(sources);
+        if new_content != *content {
+            if new_content.art_url != content.art_url || new_content.has_art != content.has_art {
+                self.thumbnail = match new_content.art_url.as_deref().and_then(|url| sources.media.art(url)).map(|data| load_art(&data)) {
+                    Some(Ok(art)) => Some(art),
+                    Some(Err(err)) => {
+                        println!("Failed to load album art: {err}");
+                        None
+                    },
+                    None => None
+                };
+            }
+            let text = new_content.text.clone();
+            *content = new_content;
+            self.set_text(text);
+            self.scroll_start = Instant::now();
+        }
+        // scrolling stops while the bar is dimmed or off, and picks up again once it is touched
+        if self.overflowing && !sources.idle {
+            self.changed = true;
+            return min(SCROLL_FRAME_MS, hold_next_update_ms);
+        }
+        min(next_update_ms, hold_next_update_ms)
+    }
+    fn action_for(&self, variant: Option<usize>) -> &ButtonAction {
+        variant.and_then(|i| self.variants[i].action.as_ref()).unwrap_or(&self.action)
+    }
+    fn set_modifiers(&mut self, modifiers: &[Modifier]) {
+        let variant = self.variants.iter().position(|variant| variant.modifiers == modifiers);
+        if variant != self.variant {
+            self.variant = variant;
+            self.changed = true;
+        }
+    }
+    // The look of the variant that is shown, if it has its own
+    fn variant_look(&self) -> Option<&(ButtonImage, String)> {
+        self.variant.and_then(|i| self.variants[i].look.as_ref())
+    }
+    fn image(&self) -> &ButtonImage {
+        self.variant_look().map_or(&self.image, |(image, _)| image)
+    }
+    // What the button shows, as reported to clients
+    fn label(&self) -> &str {
+        self.variant_look().map_or(&self.label, |(_, label)| label)
+    }
+    fn toggled(&self) -> bool {
+        self.toggle.as_ref().is_some_and(|toggle| toggle.on)
+    }
+    // Flips a toggle button into the given state without doing its action,
+    // returns false if it is not a toggle button
+    fn set_toggled(&mut self, on: bool) -> bool {
+        let Some(toggle) = self.toggle.as_mut() else {
+            return false;
+        };
+        if toggle.on != on {
+            toggle.on = on;
+            if let Some((image, label)) = toggle.other_look.as_mut() {
+                mem::swap(&mut self.image, image);
+                mem::swap(&mut self.label, label);
+            }
+            if let Some(action) = toggle.other_action.as_mut() {
+                mem::swap(&mut self.action, action);
+            }
+            self.changed = true;
+        }
+        true
+    }
+    fn set_text(&mut self, text: String) {
+        self.label = text.clone();
+        self.image = ButtonImage::Text(text);
+        self.changed = true;
+    }
+    fn set_icon(&mut self, icon: &str, theme: Option<String>) -> Result<()> {
+        self.image = try_load_image(icon, theme)?;
+        self.label = icon.to_string();
+        self.changed = true;
+        Ok(())
+    }
+    fn set_image(&mut self, data: Vec<u8>) -> Result<()> {
+        self.image = load_image_data(data)?;
+        self.label = String::new();
+        self.changed = true;
+        Ok(())
+    }
+    fn set_color(&mut self, color: Option<Color>) {
+        self.color = color;
+        self.changed = true;
+    }
+    // Returns whether the text is scrolled, because it does not fit
+    fn render(&self, c: &Context, height: i32, button_left_edge: f64, button_width: u64, y_shift: f64, icon_size: f64) -> bool {
+        match self.image() {
             ButtonImage::Text(text) => {
+                let (mut left_edge, mut width) = (button_left_edge, button_width as f64);
+                if let Some(ButtonImage::Bitmap(art)) = &self.thumbnail {
+                    let y = y_shift + ((height as f64 - icon_size) / 2.0).round();
+                    draw_bitmap(c, art, left_edge + WIDGET_MARGIN_PX, y, icon_size);
+                    left_edge += icon_size + WIDGET_MARGIN_PX;
+                    width -= icon_size + WIDGET_MARGIN_PX;
+                }
                 let extents = c.text_extents(text).unwrap();
-                c.move_to(
-                    button_left_edge + (button_width as f64 / 2.0 - extents.width() / 2.0).round(),
-                    y_shift + (height as f64 / 2.0 + extents.height() / 2.0).round()
-                );
-                c.show_text(text).unwrap();
+                let y = y_shift + (height as f64 / 2.0 + extents.height() / 2.0).round();
+                if self.widget.is_none() || extents.width() <= width - WIDGET_MARGIN_PX * 2.0 {
+                    c.move_to(left_edge + (width / 2.0 - extents.width() / 2.0).round(), y);
+                    c.show_text(text).unwrap();
+                    return false;
+                }
+                // two copies, so that the start comes back in as the end goes out
+                let period = extents.width() + SCROLL_GAP_PX;
+                let offset = (self.scroll_start.elapsed().as_secs_f64() * SCROLL_SPEED_PX) % period;
+                c.save().unwrap();
+                c.rectangle(left_edge + WIDGET_MARGIN_PX, 0.0, width - WIDGET_MARGIN_PX * 2.0, height as f64);
+                c.clip();
+                for x in [left_edge + WIDGET_MARGIN_PX - offset, left_edge + WIDGET_MARGIN_PX - offset + period] {
+                    c.move_to(x.round(), y);
+                    c.show_text(text).unwrap();
+                }
+                c.restore().unwrap();
+                true
             },
             ButtonImage::Svg(svg) => {
                 let renderer = CairoRenderer::new(&svg);
-                let x = button_left_edge + (button_width as f64 / 2.0 - (ICON_SIZE / 2) as f64).round();
-                let y = y_shift + ((height as f64 - ICON_SIZE as f64) / 2.0).round();
+                let x = button_left_edge + (button_width as f64 / 2.0 - icon_size / 2.0).round();
+                let y = y_shift + ((height as f64 - icon_size) / 2.0).round();
 
                 renderer.render_document(c,
-                    &Rectangle::new(x, y, ICON_SIZE as f64, ICON_SIZE as f64)
+                    &Rectangle::new(x, y, icon_size, icon_size)
                 ).unwrap();
+                false
             }
             ButtonImage::Bitmap(surf) => {
-                let x = button_left_edge + (button_width as f64 / 2.0 - (ICON_SIZE / 2) as f64).round();
-                let y = y_shift + ((height as f64 - ICON_SIZE as f64) / 2.0).round();
-                c.set_source_surface(surf, x, y).unwrap();
-                c.rectangle(x, y, ICON_SIZE as f64, ICON_SIZE as f64);
-                c.fill().unwrap();
+                let x = button_left_edge + (button_width as f64 / 2.0 - icon_size / 2.0).round();
+                let y = y_shift + ((height as f64 - icon_size) / 2.0).round();
+                draw_bitmap(c, surf, x, y, icon_size);
+                false
             }
         }
     }
-    fn set_active<F>(&mut self, uinput: &mut UInputHandle<F>, active: bool) where F: AsRawFd {
+    fn set_active(&mut self, ctx: &mut ActionContext, active: bool) {
         if self.active != active {
             self.active = active;
             self.changed = true;
-
-            toggle_key(uinput, self.action, active as i32);
+            if active {
+                // so that the release goes to the same action even if the modifiers change in between
+                self.pressed_variant = self.variant;
+            }
+            if self.toggle.is_none() {
+                self.action_for(self.pressed_variant).apply(ctx, active);
+            } else if active {
+                // a toggle does the action of the state it flips into
+                self.set_toggled(!self.toggled());
+                let action = self.action_for(self.pressed_variant);
+                action.apply(ctx, true);
+                action.apply(ctx, false);
+            }
         }
     }
 }
 
 #[derive(Default)]
 pub struct FunctionLayer {
+    name: String,
     buttons: Vec<(usize, Button)>,
     virtual_button_count: usize,
 }
 
 impl FunctionLayer {
-    fn with_config(cfg: Vec<ButtonConfig>) -> FunctionLayer {
+    fn with_config(name: String, cfg: Vec<ButtonConfig>) -> FunctionLayer {
         if cfg.is_empty() {
-            panic!("Invalid configuration, layer has 0 buttons");
+            panic!("Invalid configuration, layer {name} has 0 buttons");
         }
         
         let mut virtual_button_count = 0;
         FunctionLayer {
+            name,
             buttons: cfg.into_iter().scan(&mut virtual_button_count, |state, cfg| {
                 let i = **state;
                 let mut stretch = cfg.stretch.unwrap_or(1);
@@ -225,63 +679,74 @@ impl FunctionLayer {
             virtual_button_count,
         }
     }
-    fn draw(&mut self, config: &Config, width: i32, height: i32, surface: &Surface, pixel_shift: (f64, f64), complete_redraw: bool) -> Vec<ClipRect> {
+    fn set_modifiers(&mut self, modifiers: &[Modifier]) {
+        for (_, button) in &mut self.buttons {
+            button.set_modifiers(modifiers);
+        }
+    }
+    fn update_widgets(&mut self, sources: &WidgetSources) -> i32 {
+        self.buttons.iter_mut().map(|(_, button)| button.update_widget(sources)).min().unwrap_or(i32::MAX)
+    }
+    fn draw(&mut self, config: &Config, layout: &Layout, surface: &Surface, fn_locked: bool, complete_redraw: bool) -> Vec<ClipRect> {
+        let (width, height) = (layout.width, layout.height);
         let c = Context::new(&surface).unwrap();
         let mut modified_regions = if complete_redraw {
-            vec![ClipRect::new(0, 0, height as u16, width as u16)]
+            vec![ClipRect::new(0, 0, height, width)]
         } else {
             Vec::new()
         };
         c.translate(height as f64, 0.0);
         c.rotate((90.0f64).to_radians());
-        let pixel_shift_width = if config.enable_pixel_shift { PIXEL_SHIFT_WIDTH_PX } else { 0 };
-        let virtual_button_width = ((width - pixel_shift_width as i32) - (BUTTON_SPACING_PX * (self.virtual_button_count - 1) as i32)) as f64 / self.virtual_button_count as f64;
-        let radius = 8.0f64;
-        let bot = (height as f64) * 0.15;
-        let top = (height as f64) * 0.85;
-        let (pixel_shift_x, pixel_shift_y) = pixel_shift;
+        let theme = &config.theme;
+        let radius = layout.radius;
+        let pixel_shift_y = layout.shift_y();
 
         if complete_redraw {
-            c.set_source_rgb(0.0, 0.0, 0.0);
+            set_color(&c, theme.background);
             c.paint().unwrap();
         }
         c.set_font_face(&config.font_face);
-        c.set_font_size(32.0);
+        c.set_font_size(theme.font_size);
 
         for i in 0..self.buttons.len() {
-            let end = if i + 1 < self.buttons.len() {
-                self.buttons[i + 1].0
-            } else {
-                self.virtual_button_count
-            };
-            let (start, button) = &mut self.buttons[i];
-            let start = *start;
+            let rect = self.button_rect(layout, i);
+            let button = &mut self.buttons[i].1;
             
             if !button.changed && !complete_redraw {
                 continue;
             };
 
-            let left_edge = (start as f64 * (virtual_button_width + BUTTON_SPACING_PX as f64)).floor() + pixel_shift_x + (pixel_shift_width / 2) as f64;
-
-            let button_width = virtual_button_width + ((end - start - 1) as f64 * (virtual_button_width + BUTTON_SPACING_PX as f64)).floor();
+            let left_edge = rect.x;
+            let button_width = rect.width;
+            let bot = rect.y + radius;
+            let top = rect.y + rect.height - radius;
 
-            let color = if button.active {
-                BUTTON_COLOR_ACTIVE
+            let slider = match &button.action {
+                ButtonAction::Slider(slider) => Some(slider),
+                _ => None
+            };
+            // a slider's fill is what shows it being touched
+            let color = if button.active && slider.is_none() {
+                theme.button_active
+            } else if button.toggled() || button.widget.as_ref().is_some_and(|(_, content)| content.highlighted) {
+                theme.button_toggled
+            } else if let Some(color) = button.color {
+                color
             } else if config.show_button_outlines {
-                BUTTON_COLOR_INACTIVE
+                theme.button_inactive
             } else {
-                0.0
+                theme.background
             };
             if !complete_redraw {
-                c.set_source_rgb(0.0, 0.0, 0.0);
+                set_color(&c, theme.background);
                 c.rectangle(left_edge, bot - radius, button_width, top - bot + radius * 2.0);
                 c.fill().unwrap();
             }
-            c.set_source_rgb(color, color, color);
+            set_color(&c, color);
             // draw box with rounded corners
             c.new_sub_path();
             let left = left_edge + radius;
-            let right = (left_edge + button_width.ceil()) - radius;
+            let right = (left_edge + button_width) - radius;
             c.arc(
                 right,
                 bot,
@@ -312,102 +777,177 @@ impl FunctionLayer {
             );
             c.close_path();
 
-            c.fill().unwrap();
-            c.set_source_rgb(1.0, 1.0, 1.0);
-            button.render(&c, height, left_edge, button_width.ceil() as u64, pixel_shift_y);
+            if let Some(slider) = slider {
+                c.fill_preserve().unwrap();
+                c.clip();
+                set_color(&c, theme.slider_fill);
+                c.rectangle(left_edge, bot - radius, button_width * slider.fraction(), top - bot + radius * 2.0);
+                c.fill().unwrap();
+                c.reset_clip();
+            } else {
+                c.fill().unwrap();
+            }
+            set_color(&c, theme.foreground);
+            button.overflowing = button.render(&c, height as i32, left_edge, button_width as u64, pixel_shift_y, theme.icon_size);
+            if fn_locked && i == 0 {
+                set_color(&c, theme.foreground);
+                draw_lock_indicator(&c, left_edge + radius, bot - radius + 4.0 + pixel_shift_y);
+            }
+            if button.widget.as_ref().is_some_and(|(_, content)| content.charging) {
+                set_color(&c, theme.foreground);
+                draw_charge_indicator(&c, left_edge + button_width - radius - 8.0, bot - radius + 4.0 + pixel_shift_y);
+            }
 
             button.changed = false;
 
             if !complete_redraw {
-                modified_regions.push(ClipRect::new(
-                    height as u16 - top as u16 - radius as u16,
-                    left_edge as u16,
-                    height as u16 - bot as u16 + radius as u16,
-                    left_edge as u16 + button_width as u16
-                ));
+                modified_regions.push(layout.clip_rect(&rect));
             }
         }
 
         modified_regions
     }
     
-    fn hit(&self, width: u16, height: u16, x: f64, y: f64, i: Option<usize>) -> Option<usize> {
-        let virtual_button_width = (width as i32 - (BUTTON_SPACING_PX * (self.virtual_button_count - 1) as i32)) as f64 / self.virtual_button_count as f64;
-        
-        let i = i.unwrap_or_else(|| {
-            let virtual_i = (x / (width as f64 / self.virtual_button_count as f64)) as usize;
-            self.buttons.iter().position(|(start, _)| *start > virtual_i).unwrap_or(self.buttons.len()) - 1
-        });
-        if i >= self.buttons.len() {
-            return None;
-        }
-        
+    // Where a button is drawn, and can be touched
+    fn button_rect(&self, layout: &Layout, i: usize) -> Rect {
         let start = self.buttons[i].0;
         let end = if i + 1 < self.buttons.len() {
             self.buttons[i + 1].0
         } else {
             self.virtual_button_count
         };
-        
-        let left_edge = (start as f64 * (virtual_button_width + BUTTON_SPACING_PX as f64)).floor();
+        layout.button(start, end, self.virtual_button_count)
+    }
 
-        let button_width = virtual_button_width + ((end - start - 1) as f64 * (virtual_button_width + BUTTON_SPACING_PX as f64)).floor();
-        
-        if x < left_edge || x > (left_edge + button_width)
-            || y < 0.1 * height as f64 || y > 0.9 * height as f64 {
+    fn hit(&self, layout: &Layout, x: f64, y: f64, i: Option<usize>) -> Option<usize> {
+        let i = i.unwrap_or_else(|| {
+            let virtual_i = layout.virtual_index(x, self.virtual_button_count);
+            self.buttons.iter().position(|(start, _)| *start > virtual_i).unwrap_or(self.buttons.len()) - 1
+        });
+        if i >= self.buttons.len() || !self.button_rect(layout, i).contains(x, y) {
             return None;
         }
         
         Some(i)
     }
-}
 
-struct Interface;
+    // Moves a slider to where it is touched, does nothing for other buttons
+    fn slide(&mut self, layout: &Layout, i: usize, x: f64, uinput: &mut dyn KeySink, cmd_runner: &mut CommandRunner) {
+        let rect = self.button_rect(layout, i);
+        self.set_slider_fraction(i, (x - rect.x) / rect.width, uinput, cmd_runner);
+    }
 
-impl LibinputInterface for Interface {
-    fn open_restricted(&mut self, path: &Path, flags: i32) -> Result<OwnedFd, i32> {
-        let mode = flags & O_ACCMODE;
+    fn set_slider_fraction(&mut self, i: usize, fraction: f64, uinput: &mut dyn KeySink, cmd_runner: &mut CommandRunner) {
+        let button = &mut self.buttons[i].1;
+        if let ButtonAction::Slider(slider) = &mut button.action {
+            if slider.set_fraction(uinput, cmd_runner, fraction) {
+                button.changed = true;
+            }
+        }
+    }
 
-        OpenOptions::new()
-            .custom_flags(flags)
-            .read(mode == O_RDONLY || mode == O_RDWR)
-            .write(mode == O_WRONLY || mode == O_RDWR)
-            .open(path)
-            .map(|file| file.into())
-            .map_err(|err| err.raw_os_error().unwrap())
+    fn slider_fraction(&self, i: usize) -> Option<f64> {
+        match &self.buttons[i].1.action {
+            ButtonAction::Slider(slider) => Some(slider.fraction()),
+            _ => None
+        }
     }
-    fn close_restricted(&mut self, fd: OwnedFd) {
-        _ = File::from(fd);
+
+    // Runs the commands sliders held back while a previous one was running
+    fn update_sliders(&mut self, cmd_runner: &mut CommandRunner) {
+        for (_, button) in &mut self.buttons {
+            if let ButtonAction::Slider(slider) = &mut button.action {
+                slider.update(cmd_runner);
+            }
+            if let Some(on_hold) = &mut button.on_hold {
+                on_hold.update_sliders(cmd_runner);
+            }
+        }
     }
 }
 
+#[derive(Clone, Copy)]
+enum Touch {
+    // pressing a button of a layer, which is released again if the touch turns into a swipe
+    Button { layer: usize, btn: usize, start_x: f64 },
+    // on a button that is only pressed once the gesture recognizer finds the touch was a tap,
+    // which keeps track of the button itself
+    Gesture,
+    // was swiped across the bar, nothing more happens until it is lifted
+    Swiped,
+    // the overlay of a held button is shown, sliders in it move relative to where the touch was then
+    Overlay { layer: usize, btn: usize, start_x: f64, start_fraction: f64 },
+}
 
-fn emit<F>(uinput: &mut UInputHandle<F>, ty: EventKind, code: u16, value: i32) where F: AsRawFd {
-    uinput.write(&[input_event {
-        value: value,
-        type_: ty as u16,
-        code: code,
-        time: timeval {
-            tv_sec: 0,
-            tv_usec: 0
-        }
-    }]).unwrap();
+fn set_color(c: &Context, color: Color) {
+    c.set_source_rgba(color.r, color.g, color.b, color.a);
+}
+
+// Draws an image scaled to a square of the given size
+fn draw_bitmap(c: &Context, surf: &ImageSurface, x: f64, y: f64, size: f64) {
+    c.save().unwrap();
+    c.translate(x, y);
+    c.scale(size / surf.width() as f64, size / surf.height() as f64);
+    c.set_source_surface(surf, 0.0, 0.0).unwrap();
+    c.paint().unwrap();
+    c.restore().unwrap();
+}
+
+// Small padlock shown in the corner of the first button while Fn-lock is on
+fn draw_lock_indicator(c: &Context, x: f64, y: f64) {
+    c.set_line_width(1.5);
+    c.new_sub_path();
+    c.arc(x + 4.0, y + 4.0, 2.5, (180.0f64).to_radians(), (360.0f64).to_radians());
+    c.stroke().unwrap();
+    c.rectangle(x, y + 4.0, 8.0, 6.0);
+    c.fill().unwrap();
 }
 
-fn toggle_key<F>(uinput: &mut UInputHandle<F>, code: Key, value: i32) where F: AsRawFd {
-    emit(uinput, EventKind::Key, code as u16, value);
-    emit(uinput, EventKind::Synchronize, SynchronizeKind::Report as u16, 0);
+fn draw_charge_indicator(c: &Context, x: f64, y: f64) {
+    c.move_to(x + 5.0, y);
+    c.line_to(x, y + 6.0);
+    c.line_to(x + 3.5, y + 6.0);
+    c.line_to(x + 2.5, y + 11.0);
+    c.line_to(x + 8.0, y + 4.5);
+    c.line_to(x + 4.5, y + 4.5);
+    c.close_path();
+    c.fill().unwrap();
+}
+
+
+// The button a client asked for, by the name of its layer and its index
+fn find_button<'a>(layers: &'a mut [FunctionLayer], layer_mgr: &LayerManager, layer: &str, button: usize) -> Result<&'a mut Button, String> {
+    layer_mgr.index(layer)
+        .and_then(|l| layers[l].buttons.get_mut(button))
+        .map(|(_, btn)| btn)
+        .ok_or_else(|| "no such button".into())
+}
+
+// Every key the buttons of the layers can send
+fn layer_keys(layers: &[FunctionLayer]) -> Vec<Key> {
+    uinput::sorted_keys(layers.iter().flat_map(|layer| layer.buttons.iter().flat_map(|b| b.1.keys())))
+}
+
+fn toggle_key(uinput: &mut dyn KeySink, code: Key, value: i32) {
+    uinput.emit(EventKind::Key, code as u16, value);
+    uinput.emit(EventKind::Synchronize, SynchronizeKind::Report as u16, 0);
 }
 
 fn main() {
-    let mut drm = DrmBackend::open_card().unwrap();
-    let (height, width) = drm.mode().size();
+    let args = std::env::args().collect::<Vec<_>>();
+    if args.len() == 3 && args[1] == COMMAND_RUNNER_ARG {
+        commands::runner_main(&args[2]);
+        return;
+    }
+    let options = parse_options(&args[1..]);
+    let mut drm = open_display(options.offscreen.clone(), options.offscreen_size);
+    let (height, width) = drm.mode();
     let _ = panic::catch_unwind(AssertUnwindSafe(|| {
-        real_main(&mut drm)
+        real_main(drm.as_mut(), &options)
     }));
     let crash_bitmap = include_bytes!("crash_bitmap.raw");
     let mut map = drm.map().unwrap();
-    let data = map.as_mut();
+    let data = &mut **map;
     let mut wptr = 0;
     for byte in crash_bitmap {
         for i in 0..8 {
@@ -427,68 +967,178 @@ fn main() {
     sigset.wait().unwrap();
 }
 
-fn real_main(drm: &mut DrmBackend) {
-    let (height, width) = drm.mode().size();
-    let (db_width, db_height) = drm.fb_info().unwrap().size();
-    let mut uinput = UInputHandle::new(OpenOptions::new().write(true).open("/dev/uinput").unwrap());
-    let mut backlight = BacklightManager::new();
+struct Options {
+    offscreen: Option<OffscreenTarget>,
+    offscreen_size: (u16, u16),
+    // a JSON timeline of input events to play back instead of reading input devices
+    script: Option<PathBuf>,
+    // where to write the key events to instead of /dev/uinput
+    record_keys: Option<PathBuf>,
+}
+
+fn parse_options(args: &[String]) -> Options {
+    let mut options = Options {
+        offscreen: None,
+        offscreen_size: OFFSCREEN_DEFAULT_SIZE,
+        script: None,
+        record_keys: None,
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match (arg.as_str(), args.next()) {
+            ("--offscreen-png", Some(path)) => options.offscreen = Some(OffscreenTarget::Png(path.into())),
+            ("--offscreen-shm", Some(path)) => options.offscreen = Some(OffscreenTarget::Shm(path.into())),
+            ("--offscreen-size", Some(size)) => {
+                options.offscreen_size = size.split_once('x')
+                    .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+                    .unwrap_or_else(|| panic!("Invalid offscreen size {size}, expected <width>x<height>"));
+            },
+            ("--script", Some(path)) => options.script = Some(path.into()),
+            ("--record-keys", Some(path)) => options.record_keys = Some(path.into()),
+            _ => panic!("Usage: tiny-dfr [--offscreen-png <path> | --offscreen-shm <path>] [--offscreen-size <width>x<height>] [--script <timeline.json>] [--record-keys <path>]")
+        }
+    }
+    options
+}
+
+// Draws to the touch bar, unless an offscreen display is asked for
+fn open_display(target: Option<OffscreenTarget>, (width, height): (u16, u16)) -> Box<dyn DisplayBackend> {
+    match target {
+        Some(target) => Box::new(OffscreenBackend::new(target, width, height).unwrap()),
+        None => Box::new(DrmBackend::open_card().unwrap())
+    }
+}
+
+fn real_main(drm: &mut dyn DisplayBackend, options: &Options) {
+    let (height, width) = drm.mode();
+    let (db_width, db_height) = drm.fb_info().unwrap();
+    let mut backlight = match options.offscreen {
+        Some(_) => BacklightManager::offscreen(),
+        None => BacklightManager::new()
+    };
     let mut cfg_mgr = ConfigManager::new();
+    let mut state_mgr = StateManager::new();
     let (mut cfg, mut layers) = cfg_mgr.load_config(width);
+    let keys = layer_keys(&layers);
+    let uinput: Box<dyn KeySink> = match &options.record_keys {
+        Some(path) => Box::new(KeyRecorder::new(path, keys).unwrap()),
+        None => Box::new(uinput::open_device(keys))
+    };
     let mut pixel_shift = PixelShiftManager::new();
+    let macro_player = MacroPlayer::new();
+    let cmd_runner = CommandRunner::new(&cfg.command_user);
+    let mut control = ControlServer::new(&cfg.control_socket_group);
+    let dbus = match DbusService::new() {
+        Ok(dbus) => Some(dbus),
+        Err(err) => {
+            println!("Failed to connect to the system bus, the D-Bus interface is disabled: {err}");
+            None
+        }
+    };
+    let mut battery_mon = BatteryMonitor::new();
+    let uevents = match UeventMonitor::new() {
+        Ok(uevents) => Some(uevents),
+        Err(err) => {
+            println!("Failed to listen for uevents, the battery will only be polled: {err}");
+            None
+        }
+    };
 
-    // drop privileges to input and video group
-    let groups = ["input", "video"];
+    // drop privileges to input and video group, except offscreen, as the frames are
+    // written to wherever they were asked for, where nobody may not be allowed to
+    if options.offscreen.is_none() {
+        let groups = ["input", "video"];
 
-    PrivDrop::default()
-        .user("nobody")
-        .group_list(&groups)
-        .apply()
-        .unwrap_or_else(|e| { panic!("Failed to drop privileges: {}", e) });
+        PrivDrop::default()
+            .user("nobody")
+            .group_list(&groups)
+            .apply()
+            .unwrap_or_else(|e| { panic!("Failed to drop privileges: {}", e) });
+    }
 
     let mut surface = ImageSurface::create(Format::ARgb32, db_width as i32, db_height as i32).unwrap();
-    let mut active_layer = 0;
+    let layer_mgr = LayerManager::new(&cfg, &layers, state_mgr.state().fn_locked);
+    let mut active_layer = layer_mgr.active();
     let mut needs_complete_redraw = true;
 
-    let mut input_tb = Libinput::new_with_udev(Interface);
-    let mut input_main = Libinput::new_with_udev(Interface);
-    input_tb.udev_assign_seat("seat-touchbar").unwrap();
-    input_main.udev_assign_seat("seat0").unwrap();
+    let mut input: Box<dyn InputSource> = match &options.script {
+        Some(path) => Box::new(ScriptedSource::new(path).unwrap()),
+        None => Box::new(LibinputSource::new())
+    };
     let epoll = Epoll::new(EpollCreateFlags::empty()).unwrap();
-    epoll.add(input_main.as_fd(), EpollEvent::new(EpollFlags::EPOLLIN, 0)).unwrap();
-    epoll.add(input_tb.as_fd(), EpollEvent::new(EpollFlags::EPOLLIN, 1)).unwrap();
+    for (i, fd) in input.fds().into_iter().enumerate() {
+        epoll.add(fd, EpollEvent::new(EpollFlags::EPOLLIN, i as u64)).unwrap();
+    }
     epoll.add(cfg_mgr.fd(), EpollEvent::new(EpollFlags::EPOLLIN, 2)).unwrap();
-    uinput.set_evbit(EventKind::Key).unwrap();
-    for layer in &layers {
-        for button in &layer.buttons {
-            uinput.set_keybit(button.1.action).unwrap();
-        }
-    }
-    let mut dev_name_c = [0 as c_char; 80];
-    let dev_name = "Dynamic Function Row Virtual Input Device".as_bytes();
-    for i in 0..dev_name.len() {
-        dev_name_c[i] = dev_name[i] as c_char;
-    }
-    uinput.dev_setup(&uinput_setup {
-        id: input_id {
-            bustype: 0x19,
-            vendor: 0x1209,
-            product: 0x316E,
-            version: 1
-        },
-        ff_effects_max: 0,
-        name: dev_name_c
-    }).unwrap();
-    uinput.dev_create().unwrap();
-
-    let mut digitizer: Option<InputDevice> = None;
-    let mut touches = HashMap::new();
+    epoll.add(cmd_runner.fd(), EpollEvent::new(EpollFlags::EPOLLIN, 3)).unwrap();
+    epoll.add(cmd_runner.stream_fd(), EpollEvent::new(EpollFlags::EPOLLIN, 3)).unwrap();
+    epoll.add(control.fd(), EpollEvent::new(EpollFlags::EPOLLIN, 4)).unwrap();
+    if let Some(dbus) = &dbus {
+        dbus.set_layers(&layers);
+        epoll.add(dbus.fd(), EpollEvent::new(EpollFlags::EPOLLIN, 5)).unwrap();
+    }
+    if let Some(uevents) = &uevents {
+        epoll.add(uevents.fd(), EpollEvent::new(EpollFlags::EPOLLIN, 6)).unwrap();
+    }
+    let media = MediaMonitor::new().unwrap();
+    epoll.add(media.fd(), EpollEvent::new(EpollFlags::EPOLLIN, 8)).unwrap();
+
+    let mut touches: HashMap<u32, Touch> = HashMap::new();
+    let mut gestures = GestureRecognizer::new();
+    let mut modifier_state = ModifierState::new();
+    let mut focused_app = String::new();
+    let mut ctx = ActionContext {
+        uinput,
+        layer_mgr,
+        macro_player,
+        cmd_runner,
+        session: Session { workspaces: Workspaces::new(), media },
+    };
     loop {
-        if cfg_mgr.update_config(&mut cfg, &mut layers, width) {
-            active_layer = 0;
+        if let Some((new_cfg, new_layers)) = cfg_mgr.update_config(width) {
+            // held buttons are released first, so that their keys and layers do not stay pressed
+            for (_, touch) in touches.drain() {
+                let button = match touch {
+                    Touch::Button { layer, btn, .. } => &mut layers[layer].buttons[btn].1,
+                    Touch::Overlay { layer, btn, .. } => &mut layers[layer].buttons[btn].1.on_hold.as_mut().unwrap().buttons[0].1,
+                    Touch::Gesture | Touch::Swiped => continue
+                };
+                button.set_active(&mut ctx, false);
+            }
+            cfg = new_cfg;
+            layers = new_layers;
+            ctx.uinput.set_keys(layer_keys(&layers));
+            ctx.layer_mgr = LayerManager::new(&cfg, &layers, ctx.layer_mgr.fn_locked());
+            ctx.layer_mgr.set_focused_app(&focused_app);
+            active_layer = ctx.layer_mgr.active();
+            gestures = GestureRecognizer::new();
+            for layer in &mut layers {
+                layer.set_modifiers(modifier_state.modifiers());
+            }
+            if let Some(dbus) = &dbus {
+                dbus.set_layers(&layers);
+            }
             needs_complete_redraw = true;
         }
 
-        let mut next_timeout_ms = TIMEOUT_MS;
+        let mut next_timeout_ms = min(TIMEOUT_MS, ctx.macro_player.update(ctx.uinput.as_mut()));
+        next_timeout_ms = min(next_timeout_ms, battery_mon.update());
+        let widget_sources = WidgetSources { battery_mon: &battery_mon, workspaces: &ctx.session.workspaces, media: &ctx.session.media, idle: backlight.idle() };
+        for (i, layer) in layers.iter_mut().enumerate() {
+            let widget_next_timeout_ms = layer.update_widgets(&widget_sources);
+            // widgets on other layers are brought up to date before they are shown
+            if i == active_layer {
+                next_timeout_ms = min(next_timeout_ms, widget_next_timeout_ms);
+            }
+        }
+        next_timeout_ms = min(next_timeout_ms, input.next_timeout_ms());
+        if let Some(deadline) = gestures.next_deadline() {
+            next_timeout_ms = min(next_timeout_ms, deadline.saturating_duration_since(Instant::now()).as_millis() as i32 + 1);
+        }
+        let overlay = touches.values().find_map(|touch| match *touch {
+            Touch::Overlay { layer, btn, .. } => Some((layer, btn)),
+            _ => None
+        });
         if cfg.enable_pixel_shift {
             let (pixel_shift_needs_redraw, pixel_shift_next_timeout_ms) = pixel_shift.update();
             if pixel_shift_needs_redraw {
@@ -497,13 +1147,14 @@ fn real_main(drm: &mut DrmBackend) {
             next_timeout_ms = min(next_timeout_ms, pixel_shift_next_timeout_ms);
         }
 
-        if needs_complete_redraw || layers[active_layer].buttons.iter().any(|b| b.1.changed) {
-            let shift = if cfg.enable_pixel_shift {
-                pixel_shift.get()
-            } else {
-                (0.0, 0.0)
-            };
-            let clips = layers[active_layer].draw(&cfg, width as i32, height as i32, &surface, shift, needs_complete_redraw);
+        let (shown_layer, fn_locked) = match overlay {
+            Some((layer, btn)) => (layers[layer].buttons[btn].1.on_hold.as_mut().unwrap(), false),
+            None => (&mut layers[active_layer], ctx.layer_mgr.fn_locked())
+        };
+        // touches are checked against the buttons where they were last drawn
+        let layout = Layout::new(width, height, cfg.enable_pixel_shift.then(|| pixel_shift.get()), &cfg.theme);
+        if needs_complete_redraw || shown_layer.buttons.iter().any(|b| b.1.changed) {
+            let clips = shown_layer.draw(&cfg, &layout, &surface, fn_locked, needs_complete_redraw);
             let data = surface.data().unwrap();
             drm.map().unwrap().as_mut()[..data.len()].copy_from_slice(&data);
             drm.dirty(&clips).unwrap();
@@ -514,66 +1165,261 @@ fn real_main(drm: &mut DrmBackend) {
             Err(Errno::EINTR) | Ok(_) => { 0 },
             e => e.unwrap(),
         };
-        input_tb.dispatch().unwrap();
-        input_main.dispatch().unwrap();
-        for event in &mut input_tb.clone().chain(input_main.clone()) {
+        ctx.cmd_runner.update(&epoll);
+        for layer in &mut layers {
+            layer.update_sliders(&mut ctx.cmd_runner);
+        }
+        ctx.session.workspaces.update();
+        ctx.session.media.update();
+        if uevents.as_ref().is_some_and(|u| u.update().iter().any(|s| s == "power_supply")) {
+            battery_mon.invalidate();
+        }
+        if input.finished() {
+            // a scripted run is over once everything in it was handled
+            process::exit(0);
+        }
+        let mut gesture_events = Vec::new();
+        for event in input.events(width, height) {
             backlight.process_event(&event);
             match event {
-                Event::Device(DeviceEvent::Added(evt)) => {
-                    let dev = evt.device();
-                    if dev.name().contains(" Touch Bar") {
-                        digitizer = Some(dev);
+                InputEvent::Key { key, pressed } => {
+                    let state = if pressed { KeyState::Pressed } else { KeyState::Released };
+                    ctx.layer_mgr.process_key(key as u32, state);
+                    if modifier_state.process_key(key as u32, state) {
+                        for layer in &mut layers {
+                            layer.set_modifiers(modifier_state.modifiers());
+                        }
                     }
                 },
-                Event::Keyboard(KeyboardEvent::Key(key)) => {
-                    if key.key() == Key::Fn as u32 {
-                        let new_layer = match key.key_state() {
-                            KeyState::Pressed => 1,
-                            KeyState::Released => 0
-                        };
-                        if active_layer != new_layer {
-                            active_layer = new_layer;
-                            needs_complete_redraw = true;
+                InputEvent::TouchDown { .. } | InputEvent::TouchMotion { .. } | InputEvent::TouchUp { .. } if backlight.current_bl() == 0 => {},
+                InputEvent::TouchDown { slot, x, y } => {
+                    // the overlay only follows the touch that brought it up
+                    if touches.values().any(|touch| matches!(touch, Touch::Overlay { .. })) {
+                        continue;
+                    }
+                    if let Some(btn) = layers[active_layer].hit(&layout, x, y, None) {
+                        if let Some(dbus) = &dbus {
+                            dbus.button_pressed(&layers[active_layer].name, btn);
                         }
+                        let button = &mut layers[active_layer].buttons[btn].1;
+                        if let Some(options) = button.gesture_options() {
+                            touches.insert(slot, Touch::Gesture);
+                            gesture_events.extend(gestures.down(slot, (active_layer, btn), x, Instant::now(), options));
+                            continue;
+                        }
+                        touches.insert(slot, Touch::Button { layer: active_layer, btn, start_x: x });
+                        button.set_active(&mut ctx, true);
+                        layers[active_layer].slide(&layout, btn, x, ctx.uinput.as_mut(), &mut ctx.cmd_runner);
                     }
                 },
-                Event::Touch(te) => {
-                    if Some(te.device()) != digitizer || backlight.current_bl() == 0 {
-                        continue
-                    }
-                    match te {
-                        TouchEvent::Down(dn) => {
-                            let x = dn.x_transformed(width as u32);
-                            let y = dn.y_transformed(height as u32);
-                            if let Some(btn) = layers[active_layer].hit(width, height, x, y, None) {
-                                touches.insert(dn.seat_slot(), (active_layer, btn));
-                                layers[active_layer].buttons[btn].1.set_active(&mut uinput, true);
-                            }
-                        },
-                        TouchEvent::Motion(mtn) => {
-                            if !touches.contains_key(&mtn.seat_slot()) {
-                                continue;
-                            }
+                InputEvent::TouchMotion { slot, x, y } => {
+                    let Some(touch) = touches.get(&slot).copied() else {
+                        continue;
+                    };
 
-                            let x = mtn.x_transformed(width as u32);
-                            let y = mtn.y_transformed(height as u32);
-                            let (layer, btn) = *touches.get(&mtn.seat_slot()).unwrap();
-                            let hit = layers[active_layer].hit(width, height, x, y, Some(btn)).is_some();
-                            layers[layer].buttons[btn].1.set_active(&mut uinput, hit);
+                    match touch {
+                        // sliders keep following the touch even once it leaves them
+                        Touch::Button { layer, btn, .. } if matches!(layers[layer].buttons[btn].1.action, ButtonAction::Slider(_)) => {
+                            layers[layer].slide(&layout, btn, x, ctx.uinput.as_mut(), &mut ctx.cmd_runner);
                         },
-                        TouchEvent::Up(up) => {
-                            if !touches.contains_key(&up.seat_slot()) {
+                        Touch::Button { layer, btn, start_x } => {
+                            let bar_swipes = cfg.swipe_left.is_some() || cfg.swipe_right.is_some();
+                            let button = &mut layers[layer].buttons[btn].1;
+                            if let Some(gesture) = swipe(start_x, x).filter(|_| bar_swipes) {
+                                // the press is cancelled, as far as it can be once it was sent
+                                button.set_active(&mut ctx, false);
+                                touches.insert(slot, Touch::Swiped);
+                                gesture_events.push(GestureEvent { slot, target: (layer, btn), gesture, x });
                                 continue;
                             }
-                            let (layer, btn) = *touches.get(&up.seat_slot()).unwrap();
-                            layers[layer].buttons[btn].1.set_active(&mut uinput, false);
+                            let hit = layers[layer].hit(&layout, x, y, Some(btn)).is_some();
+                            layers[layer].buttons[btn].1.set_active(&mut ctx, hit);
+                        },
+                        Touch::Gesture => gesture_events.extend(gestures.motion(slot, x)),
+                        Touch::Swiped => {},
+                        Touch::Overlay { layer, btn, start_x, start_fraction } => {
+                            let hold_layer = layers[layer].buttons[btn].1.on_hold.as_mut().unwrap();
+                            let track_width = hold_layer.button_rect(&layout, 0).width;
+                            hold_layer.set_slider_fraction(0, start_fraction + (x - start_x) / track_width, ctx.uinput.as_mut(), &mut ctx.cmd_runner);
+                        }
+                    }
+                },
+                InputEvent::TouchUp { slot } => {
+                    let Some(touch) = touches.remove(&slot) else {
+                        continue;
+                    };
+                    gesture_events.extend(gestures.up(slot, Instant::now()));
+                    match touch {
+                        Touch::Button { layer, btn, .. } => {
+                            layers[layer].buttons[btn].1.set_active(&mut ctx, false);
+                        },
+                        Touch::Gesture | Touch::Swiped => {},
+                        Touch::Overlay { layer, btn, .. } => {
+                            let hold_layer = layers[layer].buttons[btn].1.on_hold.as_mut().unwrap();
+                            hold_layer.buttons[0].1.set_active(&mut ctx, false);
+                            needs_complete_redraw = true;
                         }
-                        _ => {}
                     }
                 },
                 _ => {}
             }
         }
+        gesture_events.extend(gestures.update(Instant::now()));
+        for GestureEvent { slot, target: (layer, btn), gesture, x } in gesture_events {
+            let button = &mut layers[layer].buttons[btn].1;
+            if let Some(action) = button.gesture_action(gesture) {
+                action.apply(&mut ctx, true);
+                action.apply(&mut ctx, false);
+                continue;
+            }
+            match gesture {
+                Gesture::Tap => {
+                    button.set_active(&mut ctx, true);
+                    button.set_active(&mut ctx, false);
+                },
+                Gesture::LongPress => {
+                    // the overlay only follows the touch that brought it up, and only while it lasts
+                    let still_touched = matches!(touches.get(&slot), Some(Touch::Gesture));
+                    if !still_touched || touches.values().any(|touch| matches!(touch, Touch::Overlay { .. })) {
+                        continue;
+                    }
+                    let Some(hold_layer) = button.on_hold.as_mut() else {
+                        continue;
+                    };
+                    hold_layer.buttons[0].1.set_active(&mut ctx, true);
+                    let start_fraction = hold_layer.slider_fraction(0).unwrap_or(0.0);
+                    touches.insert(slot, Touch::Overlay { layer, btn, start_x: x, start_fraction });
+                    needs_complete_redraw = true;
+                },
+                Gesture::SwipeLeft | Gesture::SwipeRight => {
+                    let action = if gesture == Gesture::SwipeLeft { &cfg.swipe_left } else { &cfg.swipe_right };
+                    if let Some(action) = action {
+                        ctx.layer_mgr.apply(action, true);
+                        ctx.layer_mgr.apply(action, false);
+                    }
+                },
+                Gesture::DoubleTap => {}
+            }
+        }
+        let mut requests = control.update(&epoll);
+        if let Some(dbus) = &dbus {
+            requests.extend(dbus.update().into_iter().map(|request| (None, Ok(request))));
+        }
+        for (client, request) in requests {
+            let result = match request {
+                Err(err) => Err(err),
+                Ok(Request::Status) => {
+                    Ok(Some(Status {
+                        active_layer: layers[active_layer].name.clone(),
+                        fn_locked: ctx.layer_mgr.fn_locked(),
+                        backlight: backlight.current_bl(),
+                        idle: backlight.idle(),
+                        active_brightness: backlight.active_brightness(&cfg),
+                        focused_app: focused_app.clone(),
+                        layers: layers.iter().map(|layer| LayerStatus {
+                            name: layer.name.clone(),
+                            buttons: layer.buttons.iter().map(|b| b.1.label().to_string()).collect(),
+                        }).collect(),
+                    }))
+                },
+                Ok(Request::Focus { app }) => {
+                    ctx.layer_mgr.set_focused_app(&app);
+                    focused_app = app;
+                    Ok(None)
+                },
+                Ok(Request::WorkspaceSocket { socket: Some(socket) }) => {
+                    ctx.session.workspaces.connect(socket.into(), &epoll).map(|_| None)
+                },
+                Ok(Request::MediaBus { uid, socket: Some(socket) }) => {
+                    ctx.session.media.connect(socket.into(), uid).map(|_| None).map_err(|e| e.to_string())
+                },
+                Ok(Request::MediaBus { socket: None, .. }) => Err("no socket was passed along".into()),
+                Ok(Request::MediaArt { url, data }) => {
+                    ctx.session.media.set_art(url, data);
+                    Ok(None)
+                },
+                Ok(Request::WorkspaceSocket { socket: None }) => Err("no socket was passed along".into()),
+                Ok(Request::Layer { name }) => {
+                    match ctx.layer_mgr.index(&name) {
+                        Some(layer) => {
+                            ctx.layer_mgr.show(layer);
+                            Ok(None)
+                        },
+                        None => Err(format!("no layer named {name}"))
+                    }
+                },
+                Ok(Request::Press { button, layer }) => {
+                    let layer = match layer {
+                        Some(name) => ctx.layer_mgr.index(&name),
+                        None => Some(active_layer)
+                    };
+                    match layer.and_then(|l| Some((l, layers[l].buttons.get_mut(button)?))) {
+                        Some((l, (_, btn))) => {
+                            btn.set_active(&mut ctx, true);
+                            btn.set_active(&mut ctx, false);
+                            if let Some(dbus) = &dbus {
+                                dbus.button_pressed(&layers[l].name, button);
+                            }
+                            Ok(None)
+                        },
+                        None => Err("no such button".into())
+                    }
+                },
+                Ok(Request::Brightness { value }) => {
+                    backlight.set_active_brightness(value);
+                    Ok(None)
+                },
+                Ok(Request::Reload) => {
+                    cfg_mgr.request_reload();
+                    Ok(None)
+                },
+                Ok(Request::SetText { layer, button, text }) => {
+                    find_button(&mut layers, &ctx.layer_mgr, &layer, button).map(|btn| {
+                        btn.set_text(text);
+                        None
+                    })
+                },
+                Ok(Request::SetIcon { layer, button, icon, theme }) => {
+                    find_button(&mut layers, &ctx.layer_mgr, &layer, button)
+                        .and_then(|btn| btn.set_icon(&icon, theme).map(|_| None).map_err(|e| format!("{e:#}")))
+                },
+                Ok(Request::SetImage { layer, button, data }) => {
+                    find_button(&mut layers, &ctx.layer_mgr, &layer, button)
+                        .and_then(|btn| btn.set_image(data).map(|_| None).map_err(|e| format!("{e:#}")))
+                },
+                Ok(Request::SetColor { layer, button, color }) => {
+                    find_button(&mut layers, &ctx.layer_mgr, &layer, button).map(|btn| {
+                        btn.set_color(color);
+                        None
+                    })
+                },
+                Ok(Request::SetToggled { layer, button, on }) => {
+                    find_button(&mut layers, &ctx.layer_mgr, &layer, button).and_then(|btn| if btn.set_toggled(on) {
+                        Ok(None)
+                    } else {
+                        Err("not a toggle button".into())
+                    })
+                }
+            };
+            match client {
+                Some(client) => control.reply(client, &result),
+                None => if let Err(err) = result {
+                    println!("D-Bus request failed: {err}");
+                }
+            }
+        }
+        let new_layer = ctx.layer_mgr.active();
+        if active_layer != new_layer {
+            active_layer = new_layer;
+            needs_complete_redraw = true;
+        }
+        if state_mgr.state().fn_locked != ctx.layer_mgr.fn_locked() {
+            state_mgr.update(State { fn_locked: ctx.layer_mgr.fn_locked() });
+            needs_complete_redraw = true;
+        }
         backlight.update_backlight(&cfg);
+        if let Some(dbus) = &dbus {
+            dbus.update_properties(&layers[active_layer].name, backlight.current_bl(), backlight.idle());
+        }
     }
 }
@@ -0,0 +1,342 @@
+use std::{
+    collections::HashMap,
+    os::{fd::AsFd, unix::net::UnixStream},
+    sync::{Arc, Mutex},
+    thread,
+};
+use anyhow::Result;
+use nix::sys::eventfd::{EfdFlags, EventFd};
+use serde::Deserialize;
+use zbus::{
+    blocking::{connection, fdo::DBusProxy, Connection, MessageIterator},
+    message::{Flags, Type},
+    zvariant::{OwnedValue, Value},
+    MatchRule, Message,
+};
+
+const PLAYER_PREFIX: &str = "org.mpris.MediaPlayer2.";
+const PLAYER_PATH: &str = "/org/mpris/MediaPlayer2";
+const PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
+// album art kept for the players, by url
+const ART_CACHE_LEN: usize = 4;
+
+#[derive(Deserialize, Clone, Copy)]
+pub enum MediaCommand {
+    PlayPause,
+    Play,
+    Pause,
+    Next,
+    Previous,
+}
+
+impl MediaCommand {
+    fn method(self) -> &'static str {
+        match self {
+            MediaCommand::PlayPause => "PlayPause",
+            MediaCommand::Play => "Play",
+            MediaCommand::Pause => "Pause",
+            MediaCommand::Next => "Next",
+            MediaCommand::Previous => "Previous",
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub struct NowPlaying {
+    pub title: String,
+    pub artist: String,
+    pub art_url: Option<String>,
+    pub playing: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    // bumped on every new connection, so that the thread of an old one does not overwrite the state
+    generation: u64,
+    // bus name of the player that is followed
+    player: Option<String>,
+    now_playing: Option<NowPlaying>,
+}
+
+fn metadata_str(metadata: &HashMap<String, OwnedValue>, key: &str) -> Option<String> {
+    match &**metadata.get(key)? {
+        Value::Str(s) => Some(s.to_string()),
+        // xesam:artist is a list
+        Value::Array(array) => Some(array.iter()
+            .filter_map(|v| match v {
+                Value::Str(s) => Some(s.as_str()),
+                _ => None
+            })
+            .collect::<Vec<_>>()
+            .join(", ")),
+        _ => None
+    }
+}
+
+fn read_player(conn: &Connection, name: &str) -> Result<NowPlaying> {
+    let reply = conn.call_method(Some(name), PLAYER_PATH, Some("org.freedesktop.DBus.Properties"), "GetAll", &(PLAYER_INTERFACE))?;
+    let props: HashMap<String, OwnedValue> = reply.body().deserialize()?;
+    let metadata: HashMap<String, OwnedValue> = match props.get("Metadata") {
+        Some(metadata) => metadata.try_clone()?.try_into()?,
+        None => HashMap::new()
+    };
+    let playing = props.get("PlaybackStatus").is_some_and(|status| matches!(&**status, Value::Str(s) if s.as_str() == "Playing"));
+    Ok(NowPlaying {
+        title: metadata_str(&metadata, "xesam:title").unwrap_or_default(),
+        artist: metadata_str(&metadata, "xesam:artist").unwrap_or_default(),
+        art_url: metadata_str(&metadata, "mpris:artUrl").filter(|url| !url.is_empty()),
+        playing,
+    })
+}
+
+// Players in the order they should be followed in: the one that most recently
+// started playing first, then the others in the order they appeared
+struct PlayerTracker {
+    players: Vec<(String, bool)>,
+}
+
+impl PlayerTracker {
+    fn refresh(&mut self, conn: &Connection, dbus: &DBusProxy) -> Result<(Option<String>, Option<NowPlaying>)> {
+        let names = dbus.list_names()?.into_iter()
+            .map(|name| name.to_string())
+            .filter(|name| name.starts_with(PLAYER_PREFIX))
+            .collect::<Vec<_>>();
+        self.players.retain(|(name, _)| names.contains(name));
+        for name in names {
+            if !self.players.iter().any(|(n, _)| *n == name) {
+                self.players.push((name, false));
+            }
+        }
+        let mut states = Vec::new();
+        for (name, was_playing) in &self.players {
+            // a player that does not answer is skipped until the next change
+            if let Ok(state) = read_player(conn, name) {
+                states.push((name.clone(), *was_playing, state));
+            }
+        }
+        for (name, was_playing, state) in &states {
+            let pos = self.players.iter().position(|(n, _)| n == name).unwrap();
+            self.players[pos].1 = state.playing;
+            if state.playing && !was_playing {
+                let player = self.players.remove(pos);
+                self.players.insert(0, player);
+            }
+        }
+        let chosen = self.players.iter()
+            .filter_map(|(name, _)| states.iter().find(|(n, _, _)| n == name))
+            .min_by_key(|(_, _, state)| !state.playing);
+        Ok(match chosen {
+            Some((name, _, state)) => (Some(name.clone()), Some(state.clone())),
+            None => (None, None)
+        })
+    }
+}
+
+fn follow_players(conn: Connection, shared: Arc<Mutex<Shared>>, wake: Arc<EventFd>, generation: u64) -> Result<()> {
+    let dbus = DBusProxy::new(&conn)?;
+    dbus.add_match_rule(MatchRule::builder()
+        .msg_type(Type::Signal)
+        .interface("org.freedesktop.DBus.Properties")?
+        .member("PropertiesChanged")?
+        .path(PLAYER_PATH)?
+        .build())?;
+    dbus.add_match_rule(MatchRule::builder()
+        .msg_type(Type::Signal)
+        .interface("org.freedesktop.DBus")?
+        .member("NameOwnerChanged")?
+        .arg0ns("org.mpris.MediaPlayer2")?
+        .build())?;
+    let messages = MessageIterator::from(&conn);
+    let mut tracker = PlayerTracker { players: Vec::new() };
+    let publish = |(player, now_playing)| {
+        let mut shared = shared.lock().unwrap();
+        if shared.generation == generation {
+            shared.player = player;
+            shared.now_playing = now_playing;
+            _ = wake.write(1);
+        }
+    };
+    publish(tracker.refresh(&conn, &dbus)?);
+    for msg in messages {
+        if msg?.header().message_type() == Type::Signal {
+            publish(tracker.refresh(&conn, &dbus)?);
+        }
+    }
+    Ok(())
+}
+
+// Follows the active MPRIS player on the session bus of the user. Like for
+// the workspace widget, the daemon cannot connect to that bus itself, so
+// a connection to it is handed over by a client running in the session.
+// Signals are waited for on a thread of its own, which wakes the main loop
+// up through an eventfd.
+pub struct MediaMonitor {
+    conn: Option<Connection>,
+    shared: Arc<Mutex<Shared>>,
+    wake: Arc<EventFd>,
+    // sent by tiny-dfr-ctl, which can read the files, the latest last
+    art: Vec<(String, Arc<Vec<u8>>)>,
+}
+
+impl MediaMonitor {
+    pub fn new() -> Result<MediaMonitor> {
+        Ok(MediaMonitor {
+            conn: None,
+            shared: Arc::new(Mutex::new(Shared::default())),
+            wake: Arc::new(EventFd::from_flags(EfdFlags::EFD_NONBLOCK)?),
+            art: Vec::new(),
+        })
+    }
+    pub fn fd(&self) -> &impl AsFd {
+        self.wake.as_ref()
+    }
+    // The uid is the one of the user the bus belongs to, that the connection
+    // was opened by, as the bus checks it against the peer credentials
+    pub fn connect(&mut self, stream: UnixStream, uid: u32) -> Result<()> {
+        if let Some(conn) = self.conn.take() {
+            _ = conn.close();
+        }
+        let generation = {
+            let mut shared = self.shared.lock().unwrap();
+            shared.generation += 1;
+            shared.player = None;
+            shared.now_playing = None;
+            shared.generation
+        };
+        let conn = connection::Builder::async_io_unix_stream(stream).user_id(uid).build()?;
+        self.conn = Some(conn.clone());
+        let (shared, wake) = (self.shared.clone(), self.wake.clone());
+        thread::spawn(move || {
+            if let Err(err) = follow_players(conn, shared.clone(), wake.clone(), generation) {
+                println!("Lost the connection to the media players: {err}");
+            }
+            let mut shared = shared.lock().unwrap();
+            if shared.generation == generation {
+                shared.player = None;
+                shared.now_playing = None;
+                _ = wake.write(1);
+            }
+        });
+        Ok(())
+    }
+    pub fn update(&self) {
+        _ = self.wake.read();
+    }
+    pub fn now_playing(&self) -> Option<NowPlaying> {
+        self.shared.lock().unwrap().now_playing.clone()
+    }
+    pub fn set_art(&mut self, url: String, data: Vec<u8>) {
+        self.art.retain(|(u, _)| *u != url);
+        if self.art.len() == ART_CACHE_LEN {
+            self.art.remove(0);
+        }
+        self.art.push((url, Arc::new(data)));
+    }
+    pub fn art(&self, url: &str) -> Option<Arc<Vec<u8>>> {
+        self.art.iter().find(|(u, _)| u == url).map(|(_, data)| data.clone())
+    }
+    // Sent without waiting for a reply, so that a stuck player does not block the main loop
+    pub fn send(&self, command: MediaCommand) {
+        let Some(conn) = &self.conn else {
+            return;
+        };
+        let Some(player) = self.shared.lock().unwrap().player.clone() else {
+            return;
+        };
+        let msg = Message::method_call(PLAYER_PATH, command.method())
+            .and_then(|builder| builder.destination(player)?.interface(PLAYER_INTERFACE)?.with_flags(Flags::NoReplyExpected))
+            .and_then(|builder| builder.build(&()));
+        if let Err(err) = msg.and_then(|msg| conn.send(&msg)) {
+            println!("Failed to send {} to the media player: {err}", command.method());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::mpsc::{self, Sender},
+        thread,
+        time::{Duration, Instant},
+    };
+    use nix::unistd::getuid;
+    use zbus::{blocking::connection, interface, zvariant::{OwnedValue, Value}};
+    use crate::test_bus::PrivateBus;
+    use super::{MediaCommand, MediaMonitor, NowPlaying, PLAYER_PATH};
+
+    struct FakePlayer {
+        status: String,
+        title: String,
+        calls: Sender<&'static str>,
+    }
+
+    #[interface(name = "org.mpris.MediaPlayer2.Player")]
+    impl FakePlayer {
+        fn next(&self) {
+            self.calls.send("Next").unwrap();
+        }
+        fn play_pause(&self) {
+            self.calls.send("PlayPause").unwrap();
+        }
+        #[zbus(property)]
+        fn playback_status(&self) -> String {
+            self.status.clone()
+        }
+        #[zbus(property)]
+        fn metadata(&self) -> HashMap<String, OwnedValue> {
+            HashMap::from([
+                ("xesam:title".to_string(), Value::from(self.title.as_str()).try_to_owned().unwrap()),
+                ("xesam:artist".to_string(), Value::from(vec!["Someone", "Someone else"]).try_to_owned().unwrap()),
+                ("mpris:artUrl".to_string(), Value::from("file:///tmp/art.png").try_to_owned().unwrap()),
+            ])
+        }
+    }
+
+    // The monitor follows the players on a thread of its own
+    #[track_caller]
+    fn wait_for(monitor: &MediaMonitor, done: impl Fn(Option<NowPlaying>) -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done(monitor.now_playing()) {
+            assert!(Instant::now() < deadline, "the media monitor did not catch up");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn follows_player() {
+        let Some(bus) = PrivateBus::start() else {
+            return;
+        };
+        let (calls, received) = mpsc::channel();
+        let player = FakePlayer { status: "Playing".into(), title: "A song".into(), calls };
+        let conn = connection::Builder::address(bus.address.as_str()).unwrap()
+            .name("org.mpris.MediaPlayer2.fake").unwrap()
+            .serve_at(PLAYER_PATH, player).unwrap()
+            .build().unwrap();
+        let mut monitor = MediaMonitor::new().unwrap();
+        monitor.connect(bus.socket(), getuid().as_raw()).unwrap();
+        wait_for(&monitor, |now_playing| now_playing.is_some());
+        let now_playing = monitor.now_playing().unwrap();
+        assert_eq!(now_playing.title, "A song");
+        assert_eq!(now_playing.artist, "Someone, Someone else");
+        assert_eq!(now_playing.art_url.as_deref(), Some("file:///tmp/art.png"));
+        assert!(now_playing.playing);
+
+        monitor.send(MediaCommand::Next);
+        assert_eq!(received.recv_timeout(Duration::from_secs(5)).unwrap(), "Next");
+        monitor.send(MediaCommand::PlayPause);
+        assert_eq!(received.recv_timeout(Duration::from_secs(5)).unwrap(), "PlayPause");
+
+        let iface = conn.object_server().interface::<_, FakePlayer>(PLAYER_PATH).unwrap();
+        iface.get_mut().status = "Paused".into();
+        iface.get_mut().title = "Another song".into();
+        zbus::block_on(iface.get().playback_status_changed(iface.signal_emitter())).unwrap();
+        zbus::block_on(iface.get().metadata_changed(iface.signal_emitter())).unwrap();
+        wait_for(&monitor, |now_playing| now_playing.is_some_and(|n| !n.playing && n.title == "Another song"));
+
+        // and nothing is playing once the player quits
+        conn.close().unwrap();
+        wait_for(&monitor, |now_playing| now_playing.is_none());
+    }
+}
@@ -0,0 +1,60 @@
+use input::event::keyboard::KeyState;
+use input_linux::Key;
+use serde::Deserialize;
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Modifier {
+    Shift,
+    Ctrl,
+    Alt,
+    Meta,
+}
+
+impl Modifier {
+    fn from_key(code: u32) -> Option<Modifier> {
+        [
+            (Key::LeftShift, Modifier::Shift), (Key::RightShift, Modifier::Shift),
+            (Key::LeftCtrl, Modifier::Ctrl), (Key::RightCtrl, Modifier::Ctrl),
+            (Key::LeftAlt, Modifier::Alt), (Key::RightAlt, Modifier::Alt),
+            (Key::LeftMeta, Modifier::Meta), (Key::RightMeta, Modifier::Meta),
+        ].into_iter().find(|(key, _)| *key as u32 == code).map(|(_, modifier)| modifier)
+    }
+}
+
+// Tracks which modifiers are held on the main keyboard
+pub struct ModifierState {
+    // held modifier keys, so that releasing one of two keys for the same modifier keeps it held
+    held_keys: Vec<u32>,
+    modifiers: Vec<Modifier>,
+}
+
+impl ModifierState {
+    pub fn new() -> ModifierState {
+        ModifierState {
+            held_keys: Vec::new(),
+            modifiers: Vec::new(),
+        }
+    }
+    // Returns true if the held modifiers changed
+    pub fn process_key(&mut self, code: u32, state: KeyState) -> bool {
+        if Modifier::from_key(code).is_none() {
+            return false;
+        }
+        self.held_keys.retain(|k| *k != code);
+        if state == KeyState::Pressed {
+            self.held_keys.push(code);
+        }
+        let mut modifiers = self.held_keys.iter().filter_map(|k| Modifier::from_key(*k)).collect::<Vec<_>>();
+        modifiers.sort();
+        modifiers.dedup();
+        if modifiers == self.modifiers {
+            return false;
+        }
+        self.modifiers = modifiers;
+        true
+    }
+    // Sorted and without duplicates
+    pub fn modifiers(&self) -> &[Modifier] {
+        &self.modifiers
+    }
+}
@@ -0,0 +1,124 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    ops::DerefMut,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    ptr, slice,
+};
+use anyhow::{anyhow, Result};
+use cairo::{Format, ImageSurface};
+use drm::control::ClipRect;
+use crate::display::DisplayBackend;
+
+const BYTES_PER_PIXEL: usize = 4;
+
+// Turns a frame, of the given mode and row stride in bytes, the way the bar is seen
+pub fn landscape(src: &[u8], stride: usize, mode: (u16, u16)) -> Result<ImageSurface> {
+    let (panel_width, panel_height) = (mode.0 as usize, mode.1 as usize);
+    let mut surf = ImageSurface::create(Format::Rgb24, panel_height as i32, panel_width as i32)?;
+    let surf_stride = surf.stride() as usize;
+    {
+        let mut dst = surf.data()?;
+        // the frame is drawn rotated by 90 degrees, see FunctionLayer::draw
+        for y in 0..panel_width {
+            for x in 0..panel_height {
+                let from = x * stride + (panel_width - 1 - y) * BYTES_PER_PIXEL;
+                let to = y * surf_stride + x * BYTES_PER_PIXEL;
+                dst[to..to + BYTES_PER_PIXEL].copy_from_slice(&src[from..from + BYTES_PER_PIXEL]);
+            }
+        }
+    }
+    Ok(surf)
+}
+
+#[derive(Clone)]
+pub enum OffscreenTarget {
+    // every frame overwrites this file, rotated the way the bar is seen
+    Png(PathBuf),
+    // the frame buffer itself is mapped from this file, so it can be read live
+    Shm(PathBuf),
+}
+
+enum Buffer {
+    Heap(Vec<u8>),
+    Mapped { ptr: *mut u8, len: usize, _file: File },
+}
+
+// Draws into memory instead of the touch bar, for running without one
+pub struct OffscreenBackend {
+    // the same way round as the touch bar's mode, which is taller than it is wide
+    mode: (u16, u16),
+    fb_size: (u32, u32),
+    target: OffscreenTarget,
+    buf: Buffer,
+}
+
+impl Drop for OffscreenBackend {
+    fn drop(&mut self) {
+        if let Buffer::Mapped { ptr, len, .. } = self.buf {
+            unsafe { libc::munmap(ptr as *mut libc::c_void, len) };
+        }
+    }
+}
+
+impl OffscreenBackend {
+    // The size is the one of the bar as it is seen, wider than it is tall
+    pub fn new(target: OffscreenTarget, width: u16, height: u16) -> Result<OffscreenBackend> {
+        // like the dumb buffer of the touch bar, rows are padded
+        let fb_size = ((height as u32).next_multiple_of(64), width as u32);
+        let len = fb_size.0 as usize * fb_size.1 as usize * BYTES_PER_PIXEL;
+        let buf = match &target {
+            OffscreenTarget::Png(_) => Buffer::Heap(vec![0; len]),
+            OffscreenTarget::Shm(path) => {
+                let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+                file.set_len(len as u64)?;
+                let ptr = unsafe {
+                    libc::mmap(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED, file.as_raw_fd(), 0)
+                };
+                if ptr == libc::MAP_FAILED {
+                    return Err(anyhow!("Failed to map {}: {}", path.display(), std::io::Error::last_os_error()));
+                }
+                Buffer::Mapped { ptr: ptr as *mut u8, len, _file: file }
+            }
+        };
+        Ok(OffscreenBackend { mode: (height, width), fb_size, target, buf })
+    }
+    fn data(&self) -> &[u8] {
+        match &self.buf {
+            Buffer::Heap(data) => data,
+            Buffer::Mapped { ptr, len, .. } => unsafe { slice::from_raw_parts(*ptr, *len) }
+        }
+    }
+    fn data_mut(&mut self) -> &mut [u8] {
+        match &mut self.buf {
+            Buffer::Heap(data) => data,
+            Buffer::Mapped { ptr, len, .. } => unsafe { slice::from_raw_parts_mut(*ptr, *len) }
+        }
+    }
+    fn write_png(&self, path: &Path) -> Result<()> {
+        let surf = landscape(self.data(), self.fb_size.0 as usize * BYTES_PER_PIXEL, self.mode)?;
+        // written next to it and renamed, so that readers never see half a frame
+        let tmp = path.with_extension("png.tmp");
+        surf.write_to_png(&mut File::create(&tmp)?)?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+}
+
+impl DisplayBackend for OffscreenBackend {
+    fn mode(&self) -> (u16, u16) {
+        self.mode
+    }
+    fn fb_info(&self) -> Result<(u32, u32)> {
+        Ok(self.fb_size)
+    }
+    fn map(&mut self) -> Result<Box<dyn DerefMut<Target = [u8]> + '_>> {
+        Ok(Box::new(self.data_mut()))
+    }
+    fn dirty(&mut self, _clips: &[ClipRect]) -> Result<()> {
+        match &self.target {
+            OffscreenTarget::Png(path) => self.write_png(path),
+            OffscreenTarget::Shm(_) => Ok(())
+        }
+    }
+}
@@ -17,7 +17,7 @@ const ANIMATION_DURATION_MS: i32 = 4000; // should be a multiple of ANIMATION_IN
 pub const PIXEL_SHIFT_WIDTH_PX: u64 = 22; // should be divisible by 2
 // in y direction we can't really shift by a lot since icons still need to appear centered,
 // 2 pixels in each direction seems to be the maximum before it gets really visible.
-const PIXEL_SHIFT_HEIGHT_PX: u64 = 4; // should be divisible by 2
+pub const PIXEL_SHIFT_HEIGHT_PX: u64 = 4; // should be divisible by 2
 
 #[derive(Clone, Copy)]
 enum ShiftState {
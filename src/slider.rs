@@ -0,0 +1,129 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::PathBuf,
+};
+use input_linux::Key;
+use serde::Deserialize;
+use crate::commands::{CommandConfig, CommandRunner};
+use crate::sysfs::{class_dir, read_attr};
+use crate::toggle_key;
+use crate::uinput::KeySink;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct SliderConfig {
+    // tapped once for every step the value goes up or down
+    pub up: Option<Key>,
+    pub down: Option<Key>,
+    // run with {value} replaced by the new value whenever it changes
+    pub command: Option<String>,
+    // name of a device in /sys/class/backlight, whose brightness is set directly
+    pub backlight: Option<String>,
+    pub min: Option<i32>,
+    pub max: Option<i32>,
+    pub step: Option<i32>,
+    // the value shown before the slider is first touched
+    pub value: Option<i32>,
+}
+
+enum SliderTarget {
+    Keys { up: Key, down: Key },
+    // only one run at a time, the latest value waits for it in pending
+    Command { command: String, running: Option<u64>, pending: Option<i32> },
+    Backlight(PathBuf),
+}
+
+// Maps the horizontal position of a touch on the button to a value. Only a
+// backlight slider reads back the value it controls, when it is created, the
+// others only reflect what was last set through them.
+pub struct Slider {
+    target: SliderTarget,
+    min: i32,
+    max: i32,
+    step: i32,
+    value: i32,
+}
+
+impl Slider {
+    pub fn with_config(cfg: SliderConfig) -> Slider {
+        let target = match (cfg.up, cfg.down, cfg.command, cfg.backlight) {
+            (Some(up), Some(down), None, None) => SliderTarget::Keys { up, down },
+            (None, None, Some(command), None) => SliderTarget::Command { command, running: None, pending: None },
+            (None, None, None, Some(name)) => {
+                let path = class_dir("backlight").join(&name);
+                if !path.exists() {
+                    panic!("Invalid configuration, backlight {name} does not exist");
+                }
+                SliderTarget::Backlight(path)
+            },
+            _ => panic!("Invalid configuration, a slider must have either both Up and Down, Command, or Backlight")
+        };
+        let (max, step, value) = match &target {
+            SliderTarget::Backlight(path) => {
+                let max = cfg.max.unwrap_or(read_attr(path, "max_brightness") as i32);
+                (max, cfg.step.unwrap_or((max / 20).max(1)), Some(read_attr(path, "brightness") as i32))
+            },
+            _ => (cfg.max.unwrap_or(100), cfg.step.unwrap_or(5), cfg.value)
+        };
+        let min = cfg.min.unwrap_or(0);
+        if max <= min || step <= 0 {
+            panic!("Invalid configuration, a slider must have Min < Max and a positive Step");
+        }
+        let value = value.unwrap_or((min + max) / 2).clamp(min, max);
+        Slider { target, min, max, step, value }
+    }
+    pub fn keys(&self) -> Vec<Key> {
+        match self.target {
+            SliderTarget::Keys { up, down } => vec![up, down],
+            _ => Vec::new()
+        }
+    }
+    // How far along the track the value is, from 0 to 1
+    pub fn fraction(&self) -> f64 {
+        (self.value - self.min) as f64 / (self.max - self.min) as f64
+    }
+    // Moves the value to the given fraction of the track, returns whether it changed
+    pub fn set_fraction(&mut self, uinput: &mut dyn KeySink, cmd_runner: &mut CommandRunner, fraction: f64) -> bool {
+        let steps = (fraction.clamp(0.0, 1.0) * (self.max - self.min) as f64 / self.step as f64).round() as i32;
+        let value = (self.min + steps * self.step).min(self.max);
+        if value == self.value {
+            return false;
+        }
+        match &mut self.target {
+            SliderTarget::Keys { up, down } => {
+                let key = if value > self.value { *up } else { *down };
+                for _ in 0..((value - self.value).abs() + self.step - 1) / self.step {
+                    toggle_key(uinput, key, 1);
+                    toggle_key(uinput, key, 0);
+                }
+            },
+            SliderTarget::Command { pending, .. } => {
+                *pending = Some(value);
+                self.update(cmd_runner);
+            },
+            SliderTarget::Backlight(path) => {
+                if let Err(err) = fs::write(path.join("brightness"), format!("{value}\n")) {
+                    println!("Failed to set brightness of {}: {err}", path.display());
+                }
+            }
+        }
+        self.value = value;
+        true
+    }
+    // Runs the command for the latest value once the previous run exited, so that
+    // dragging the slider does not start a process for every step
+    pub fn update(&mut self, cmd_runner: &mut CommandRunner) {
+        let SliderTarget::Command { command, running, pending } = &mut self.target else {
+            return;
+        };
+        if running.is_some_and(|id| cmd_runner.is_running(id)) {
+            return;
+        }
+        *running = pending.take().and_then(|value| cmd_runner.run(&CommandConfig {
+            run: command.replace("{value}", &value.to_string()),
+            timeout: None,
+            env: BTreeMap::new(),
+        }));
+    }
+}
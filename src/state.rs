@@ -0,0 +1,65 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use crate::config::system_path;
+
+const STATE_DIR: &str = "/var/lib/tiny-dfr";
+const STATE_PATH: &str = "/var/lib/tiny-dfr/state.toml";
+
+#[derive(Serialize, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "PascalCase", default)]
+pub struct State {
+    pub fn_locked: bool,
+}
+
+// The file is opened once before privileges are dropped,
+// and then rewritten in place through the same descriptor.
+pub struct StateManager {
+    file: Option<File>,
+    state: State,
+}
+
+fn open_state_file() -> Result<File> {
+    fs::create_dir_all(system_path(STATE_DIR))?;
+    Ok(OpenOptions::new().read(true).write(true).create(true).truncate(false).open(system_path(STATE_PATH))?)
+}
+
+impl StateManager {
+    pub fn new() -> StateManager {
+        let mut file = match open_state_file() {
+            Ok(file) => Some(file),
+            Err(err) => {
+                println!("Failed to open {STATE_PATH}, state will not be persisted: {err}");
+                None
+            }
+        };
+        let mut contents = String::new();
+        if let Some(file) = file.as_mut() {
+            _ = file.read_to_string(&mut contents);
+        }
+        let state = toml::from_str(&contents).unwrap_or_default();
+        StateManager { file, state }
+    }
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+    pub fn update(&mut self, state: State) {
+        if self.state == state {
+            return;
+        }
+        self.state = state;
+        let Some(file) = self.file.as_mut() else {
+            return;
+        };
+        let contents = toml::to_string(&self.state).unwrap();
+        let res = file.set_len(0)
+            .and_then(|_| file.seek(SeekFrom::Start(0)))
+            .and_then(|_| file.write_all(contents.as_bytes()));
+        if let Err(err) = res {
+            println!("Failed to write {STATE_PATH}: {err}");
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use std::{
+    env, fs,
+    os::fd::{AsFd, AsRawFd, OwnedFd},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+use anyhow::Result;
+use nix::{
+    errno::Errno,
+    sys::socket::{bind, recv, socket, AddressFamily, MsgFlags, NetlinkAddr, SockFlag, SockProtocol, SockType},
+};
+
+// Overrides where sysfs is looked up, so that a fake tree can be used for testing
+const SYSFS_ROOT_ENV: &str = "TINY_DFR_SYSFS_ROOT";
+// Not every driver sends a uevent when the capacity changes
+const BATTERY_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+pub fn class_dir(class: &str) -> PathBuf {
+    let root = env::var_os(SYSFS_ROOT_ENV).map(PathBuf::from).unwrap_or_else(|| PathBuf::from("/sys"));
+    root.join("class").join(class)
+}
+
+pub fn read_attr(path: &Path, attr: &str) -> u32 {
+    fs::read_to_string(path.join(attr))
+        .unwrap_or_else(|_| panic!("Failed to read {attr}"))
+        .trim()
+        .parse::<u32>()
+        .unwrap_or_else(|_| panic!("Failed to parse {attr}"))
+}
+
+fn try_read_attr(path: &Path, attr: &str) -> Option<String> {
+    fs::read_to_string(path.join(attr)).ok().map(|s| s.trim().to_string())
+}
+
+// Receives the kernel's device change notifications
+pub struct UeventMonitor {
+    socket: OwnedFd,
+}
+
+impl UeventMonitor {
+    pub fn new() -> Result<UeventMonitor> {
+        let socket = socket(
+            AddressFamily::Netlink, SockType::Datagram,
+            SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC,
+            SockProtocol::NetlinkKObjectUEvent
+        )?;
+        // multicast group 1 carries the events sent by the kernel
+        bind(socket.as_raw_fd(), &NetlinkAddr::new(0, 1))?;
+        Ok(UeventMonitor { socket })
+    }
+    pub fn fd(&self) -> &impl AsFd {
+        &self.socket
+    }
+    // Returns the subsystems of the devices that changed since the last call
+    pub fn update(&self) -> Vec<String> {
+        let mut subsystems = Vec::new();
+        let mut buf = [0u8; 8192];
+        loop {
+            let len = match recv(self.socket.as_raw_fd(), &mut buf, MsgFlags::empty()) {
+                Ok(len) => len,
+                Err(Errno::EAGAIN) => break,
+                Err(err) => {
+                    println!("Failed to receive uevent: {err}");
+                    break;
+                }
+            };
+            // ACTION@DEVPATH followed by KEY=VALUE pairs, all NUL terminated
+            let subsystem = buf[..len].split(|b| *b == 0)
+                .find_map(|field| field.strip_prefix(b"SUBSYSTEM="))
+                .map(|s| String::from_utf8_lossy(s).into_owned());
+            if let Some(subsystem) = subsystem {
+                if !subsystems.contains(&subsystem) {
+                    subsystems.push(subsystem);
+                }
+            }
+        }
+        subsystems
+    }
+}
+
+pub struct BatteryStatus {
+    pub capacity: u32,
+    pub charging: bool,
+}
+
+// The first system battery among the power supplies in dir
+fn read_battery(dir: &Path) -> Option<BatteryStatus> {
+    for entry in fs::read_dir(dir).ok()? {
+        let path = entry.ok()?.path();
+        if try_read_attr(&path, "type").as_deref() != Some("Battery") {
+            continue;
+        }
+        // skip the batteries of wireless mice, keyboards and the like
+        if try_read_attr(&path, "scope").as_deref() == Some("Device") {
+            continue;
+        }
+        let Some(capacity) = try_read_attr(&path, "capacity").and_then(|c| c.parse().ok()) else {
+            continue;
+        };
+        let charging = try_read_attr(&path, "status").as_deref() == Some("Charging");
+        return Some(BatteryStatus { capacity, charging });
+    }
+    None
+}
+
+pub struct BatteryMonitor {
+    dir: PathBuf,
+    battery: Option<BatteryStatus>,
+    next_read: Instant,
+}
+
+impl BatteryMonitor {
+    pub fn new() -> BatteryMonitor {
+        BatteryMonitor::in_dir(class_dir("power_supply"))
+    }
+    fn in_dir(dir: PathBuf) -> BatteryMonitor {
+        BatteryMonitor {
+            battery: read_battery(&dir),
+            dir,
+            next_read: Instant::now() + BATTERY_POLL_INTERVAL,
+        }
+    }
+    // Should be called when a power_supply uevent is received
+    pub fn invalidate(&mut self) {
+        self.next_read = Instant::now();
+    }
+    // Rereads the battery if needed, and returns how long until it should be reread
+    pub fn update(&mut self) -> i32 {
+        let now = Instant::now();
+        if now >= self.next_read {
+            self.battery = read_battery(&self.dir);
+            self.next_read = now + BATTERY_POLL_INTERVAL;
+        }
+        (self.next_read - now).as_millis() as i32
+    }
+    pub fn battery(&self) -> Option<&BatteryStatus> {
+        self.battery.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+    use tempfile::TempDir;
+    use super::{read_battery, BatteryMonitor};
+
+    fn add_supply(dir: &Path, name: &str, attrs: &[(&str, &str)]) {
+        let path = dir.join(name);
+        fs::create_dir_all(&path).unwrap();
+        for (attr, value) in attrs {
+            fs::write(path.join(attr), format!("{value}\n")).unwrap();
+        }
+    }
+
+    #[test]
+    fn no_battery() {
+        let dir = TempDir::new().unwrap();
+        assert!(read_battery(&dir.path().join("missing")).is_none());
+        assert!(read_battery(dir.path()).is_none());
+        add_supply(dir.path(), "ADP1", &[("type", "Mains"), ("online", "1")]);
+        assert!(read_battery(dir.path()).is_none());
+    }
+
+    #[test]
+    fn battery_status() {
+        let dir = TempDir::new().unwrap();
+        add_supply(dir.path(), "ADP1", &[("type", "Mains"), ("online", "1")]);
+        add_supply(dir.path(), "BAT0", &[("type", "Battery"), ("capacity", "57"), ("status", "Charging")]);
+        let battery = read_battery(dir.path()).unwrap();
+        assert_eq!((battery.capacity, battery.charging), (57, true));
+
+        add_supply(dir.path(), "BAT0", &[("capacity", "100"), ("status", "Full")]);
+        let battery = read_battery(dir.path()).unwrap();
+        assert_eq!((battery.capacity, battery.charging), (100, false));
+    }
+
+    #[test]
+    fn skips_device_batteries() {
+        let dir = TempDir::new().unwrap();
+        add_supply(dir.path(), "hid-mouse-battery", &[("type", "Battery"), ("scope", "Device"), ("capacity", "10")]);
+        assert!(read_battery(dir.path()).is_none());
+        add_supply(dir.path(), "BAT0", &[("type", "Battery"), ("capacity", "80"), ("status", "Discharging")]);
+        assert_eq!(read_battery(dir.path()).unwrap().capacity, 80);
+    }
+
+    #[test]
+    fn monitor_rereads_when_invalidated() {
+        let dir = TempDir::new().unwrap();
+        let mut monitor = BatteryMonitor::in_dir(dir.path().into());
+        assert!(monitor.battery().is_none());
+        // polled only once a minute, unless a uevent says it changed
+        assert!(monitor.update() > 0);
+
+        add_supply(dir.path(), "BAT0", &[("type", "Battery"), ("capacity", "42"), ("status", "Discharging")]);
+        monitor.update();
+        assert!(monitor.battery().is_none());
+        monitor.invalidate();
+        monitor.update();
+        let battery = monitor.battery().unwrap();
+        assert_eq!((battery.capacity, battery.charging), (42, false));
+
+        fs::remove_dir_all(dir.path().join("BAT0")).unwrap();
+        monitor.invalidate();
+        monitor.update();
+        assert!(monitor.battery().is_none());
+    }
+}
@@ -0,0 +1,50 @@
+use std::{
+    io::{BufRead, BufReader, ErrorKind},
+    os::{linux::net::SocketAddrExt, unix::net::{SocketAddr, UnixStream}},
+    process::{Child, Command, Stdio},
+};
+
+// A dbus-daemon of its own for a test, stopped when dropped
+pub struct PrivateBus {
+    daemon: Child,
+    pub address: String,
+}
+
+impl PrivateBus {
+    // Returns None if dbus-daemon is not installed, for the test to be skipped
+    pub fn start() -> Option<PrivateBus> {
+        let daemon = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn();
+        let mut daemon = match daemon {
+            Ok(daemon) => daemon,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                println!("dbus-daemon is not installed, skipping");
+                return None;
+            },
+            Err(err) => panic!("Failed to start dbus-daemon: {err}")
+        };
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take().unwrap()).read_line(&mut address).unwrap();
+        Some(PrivateBus { daemon, address: address.trim().into() })
+    }
+    // A connection to the bus, for what is handed one by tiny-dfr-ctl
+    pub fn socket(&self) -> UnixStream {
+        let params = self.address.strip_prefix("unix:").expect("dbus-daemon should listen on a unix socket");
+        let addr = params.split(',').find_map(|param| match param.split_once('=') {
+            Some(("path", path)) => SocketAddr::from_pathname(path).ok(),
+            Some(("abstract", name)) => SocketAddr::from_abstract_name(name).ok(),
+            _ => None
+        }).unwrap();
+        UnixStream::connect_addr(&addr).unwrap()
+    }
+}
+
+impl Drop for PrivateBus {
+    fn drop(&mut self) {
+        _ = self.daemon.kill();
+        _ = self.daemon.wait();
+    }
+}
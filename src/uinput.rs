@@ -0,0 +1,121 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+};
+use anyhow::Result;
+use input_linux::{uinput::UInputHandle, EventKind, Key};
+use input_linux_sys::{uinput_setup, input_id, timeval, input_event};
+use libc::c_char;
+
+pub const DEVICE_NAME: &str = "Dynamic Function Row Virtual Input Device";
+
+// Where the key presses of the buttons go
+pub trait KeySink {
+    fn emit(&mut self, ty: EventKind, code: u16, value: i32);
+    // Changes the keys that can be sent, for when the config is reloaded
+    fn set_keys(&mut self, keys: Vec<Key>);
+}
+
+// The keys the buttons can send, each once and in order, which the virtual
+// keyboard has to be created with, as the kernel drops any other key
+pub fn sorted_keys(keys: impl IntoIterator<Item = Key>) -> Vec<Key> {
+    let mut keys = keys.into_iter().collect::<Vec<_>>();
+    keys.sort_by_key(|key| *key as u16);
+    keys.dedup();
+    keys
+}
+
+pub struct VirtualKeyboard {
+    uinput: UInputHandle<File>,
+    keys: Vec<Key>,
+}
+
+impl KeySink for VirtualKeyboard {
+    fn emit(&mut self, ty: EventKind, code: u16, value: i32) {
+        self.uinput.write(&[input_event {
+            value,
+            type_: ty as u16,
+            code,
+            time: timeval {
+                tv_sec: 0,
+                tv_usec: 0
+            }
+        }]).unwrap();
+    }
+    // The device is created again through the same file, as the daemon
+    // can no longer open /dev/uinput once it dropped its privileges
+    fn set_keys(&mut self, keys: Vec<Key>) {
+        if keys == self.keys {
+            return;
+        }
+        let result = self.uinput.dev_destroy()
+            .map_err(anyhow::Error::from)
+            .and_then(|_| create_device(&self.uinput, &keys));
+        if let Err(err) = result {
+            println!("Failed to recreate the virtual keyboard with the new keys: {err}");
+        }
+        self.keys = keys;
+    }
+}
+
+fn create_device(uinput: &UInputHandle<File>, keys: &[Key]) -> Result<()> {
+    uinput.set_evbit(EventKind::Key)?;
+    for key in keys {
+        uinput.set_keybit(*key)?;
+    }
+    let mut dev_name_c = [0 as c_char; 80];
+    let dev_name = DEVICE_NAME.as_bytes();
+    for i in 0..dev_name.len() {
+        dev_name_c[i] = dev_name[i] as c_char;
+    }
+    uinput.dev_setup(&uinput_setup {
+        id: input_id {
+            bustype: 0x19,
+            vendor: 0x1209,
+            product: 0x316E,
+            version: 1
+        },
+        ff_effects_max: 0,
+        name: dev_name_c
+    })?;
+    uinput.dev_create()?;
+    Ok(())
+}
+
+// Creates the virtual keyboard, able to send the given keys
+pub fn open_device(keys: Vec<Key>) -> VirtualKeyboard {
+    let uinput = UInputHandle::new(OpenOptions::new().write(true).open("/dev/uinput").unwrap());
+    create_device(&uinput, &keys).unwrap();
+    VirtualKeyboard { uinput, keys }
+}
+
+// Writes the key events that would have been sent to a file instead, one per
+// line as the name of the key and 1 for a press or 0 for a release, so that
+// what a scripted run pressed can be checked. Like the kernel, it drops the
+// keys the virtual keyboard would not have been created with.
+pub struct KeyRecorder {
+    file: File,
+    keys: Vec<Key>,
+}
+
+impl KeyRecorder {
+    pub fn new(path: &Path, keys: Vec<Key>) -> Result<KeyRecorder> {
+        Ok(KeyRecorder { file: File::create(path)?, keys })
+    }
+}
+
+impl KeySink for KeyRecorder {
+    fn emit(&mut self, ty: EventKind, code: u16, value: i32) {
+        if ty != EventKind::Key {
+            return;
+        }
+        match Key::from_code(code) {
+            Ok(key) if self.keys.contains(&key) => writeln!(self.file, "{key:?} {value}").unwrap(),
+            _ => {}
+        }
+    }
+    fn set_keys(&mut self, keys: Vec<Key>) {
+        self.keys = keys;
+    }
+}
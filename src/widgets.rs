@@ -0,0 +1,120 @@
+use std::{
+    ffi::CString,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use libc::c_char;
+use serde::Deserialize;
+use crate::{media::MediaMonitor, sysfs::BatteryMonitor, workspaces::Workspaces};
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum WidgetKind {
+    Clock,
+    Battery,
+    Workspace,
+    Media,
+}
+
+const DEFAULT_CLOCK_FORMAT: &str = "%H:%M";
+
+pub enum Widget {
+    Clock {
+        format: CString,
+        // whether the format has a field that changes every second
+        seconds: bool,
+    },
+    Battery,
+    // the workspace at this position in the list, counting from 0
+    Workspace { index: usize },
+    Media,
+}
+
+#[derive(PartialEq, Default)]
+pub struct WidgetContent {
+    pub text: String,
+    // shows a charge indicator next to the text
+    pub charging: bool,
+    pub highlighted: bool,
+    // shown next to the text, once tiny-dfr-ctl sent it
+    pub art_url: Option<String>,
+    pub has_art: bool,
+}
+
+// What widgets show their contents from
+pub struct WidgetSources<'a> {
+    pub battery_mon: &'a BatteryMonitor,
+    pub workspaces: &'a Workspaces,
+    pub media: &'a MediaMonitor,
+    // whether the bar is dimmed or off
+    pub idle: bool,
+}
+
+fn format_time(format: &CString, time: libc::time_t) -> String {
+    let mut buf = [0u8; 128];
+    let len = unsafe {
+        let mut tm = std::mem::zeroed();
+        libc::localtime_r(&time, &mut tm);
+        libc::strftime(buf.as_mut_ptr() as *mut c_char, buf.len(), format.as_ptr(), &tm)
+    };
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+impl Widget {
+    pub fn new(kind: WidgetKind, format: Option<String>, workspace: Option<usize>) -> Widget {
+        if workspace.is_some() && !matches!(kind, WidgetKind::Workspace) {
+            panic!("Invalid configuration, Workspace can only be used with the workspace widget");
+        }
+        match kind {
+            WidgetKind::Clock => {
+                let format = format.unwrap_or_else(|| DEFAULT_CLOCK_FORMAT.into());
+                let seconds = ["%S", "%T", "%s", "%r", "%X", "%c"].iter().any(|f| format.contains(f));
+                let format = match CString::new(format) {
+                    Ok(format) => format,
+                    Err(_) => panic!("Invalid configuration, clock Format must not contain NUL characters")
+                };
+                Widget::Clock { format, seconds }
+            },
+            WidgetKind::Battery => Widget::Battery,
+            WidgetKind::Workspace => match workspace {
+                Some(number) if number > 0 => Widget::Workspace { index: number - 1 },
+                _ => panic!("Invalid configuration, a workspace widget must have a Workspace number starting at 1")
+            },
+            WidgetKind::Media => Widget::Media
+        }
+    }
+    // Returns what to show, and how long it stays valid for in milliseconds
+    pub fn update(&mut self, sources: &WidgetSources) -> (WidgetContent, i32) {
+        match self {
+            Widget::Clock { format, seconds } => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+                let period_ms = if *seconds { 1000 } else { 60 * 1000 };
+                let next_change_ms = period_ms - (now.as_millis() % period_ms as u128) as i32;
+                let text = format_time(format, now.as_secs() as libc::time_t);
+                (WidgetContent { text, ..WidgetContent::default() }, next_change_ms)
+            },
+            // the battery monitor, workspace socket and media thread take care of waking the main loop up
+            Widget::Battery => match sources.battery_mon.battery() {
+                Some(battery) => (WidgetContent { text: format!("{}%", battery.capacity), charging: battery.charging, ..WidgetContent::default() }, i32::MAX),
+                None => (WidgetContent { text: "--".into(), ..WidgetContent::default() }, i32::MAX)
+            },
+            // buttons past the last workspace are left blank
+            Widget::Workspace { index } => match sources.workspaces.get(*index) {
+                Some(workspace) => (WidgetContent { text: workspace.name.clone(), highlighted: workspace.focused, ..WidgetContent::default() }, i32::MAX),
+                None => (WidgetContent::default(), i32::MAX)
+            },
+            // left blank while nothing is playing
+            Widget::Media => match sources.media.now_playing() {
+                Some(track) => {
+                    let text = match (track.title.is_empty(), track.artist.is_empty()) {
+                        (false, false) => format!("{} - {}", track.title, track.artist),
+                        (false, true) => track.title,
+                        (true, _) => track.artist
+                    };
+                    let has_art = track.art_url.as_deref().is_some_and(|url| sources.media.art(url).is_some());
+                    (WidgetContent { text, art_url: track.art_url, has_art, ..WidgetContent::default() }, i32::MAX)
+                },
+                None => (WidgetContent::default(), i32::MAX)
+            }
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use std::{
+    io::{ErrorKind, Read, Write},
+    os::{fd::AsFd, unix::net::UnixStream},
+};
+use nix::sys::epoll::{Epoll, EpollEvent, EpollFlags};
+use serde::Deserialize;
+use crate::i3_ipc::{self, EVENT_BIT, GET_WORKSPACES, HEADER_LEN, RUN_COMMAND, SUBSCRIBE};
+
+const WORKSPACES_TOKEN: u64 = 7;
+
+#[derive(Deserialize)]
+pub struct Workspace {
+    pub name: String,
+    pub focused: bool,
+}
+
+// Follows the workspaces of sway or i3 through their IPC socket. The daemon
+// cannot reach the socket itself, the connection is handed to it by a client
+// running in the user session instead.
+pub struct Workspaces {
+    stream: Option<UnixStream>,
+    buf: Vec<u8>,
+    workspaces: Vec<Workspace>,
+}
+
+impl Workspaces {
+    pub fn new() -> Workspaces {
+        Workspaces {
+            stream: None,
+            buf: Vec::new(),
+            workspaces: Vec::new(),
+        }
+    }
+    // Replaces the current connection, if any
+    pub fn connect(&mut self, mut stream: UnixStream, epoll: &Epoll) -> Result<(), String> {
+        self.disconnect();
+        stream.set_nonblocking(true).map_err(|e| e.to_string())?;
+        epoll.add(stream.as_fd(), EpollEvent::new(EpollFlags::EPOLLIN, WORKSPACES_TOKEN)).map_err(|e| e.to_string())?;
+        // kept only once subscribed, dropping it on failure also removes it from the epoll set
+        for (msg_type, payload) in [(SUBSCRIBE, r#"["workspace"]"#), (GET_WORKSPACES, "")] {
+            stream.write_all(&i3_ipc::encode(msg_type, payload)).map_err(|e| e.to_string())?;
+        }
+        self.stream = Some(stream);
+        Ok(())
+    }
+    fn disconnect(&mut self) {
+        // closing the stream also removes it from the epoll set
+        self.stream = None;
+        self.buf.clear();
+        self.workspaces.clear();
+    }
+    fn send(&mut self, msg_type: u32, payload: &str) {
+        let Some(stream) = &mut self.stream else {
+            return;
+        };
+        if let Err(err) = stream.write_all(&i3_ipc::encode(msg_type, payload)) {
+            println!("Failed to write to the workspace socket: {err}");
+            self.disconnect();
+        }
+    }
+    // Switches to the workspace at the given position, if there is one
+    pub fn switch(&mut self, index: usize) {
+        let Some(workspace) = self.workspaces.get(index) else {
+            return;
+        };
+        let name = workspace.name.replace('\\', "\\\\").replace('"', "\\\"");
+        self.send(RUN_COMMAND, &format!("workspace \"{name}\""));
+    }
+    pub fn get(&self, index: usize) -> Option<&Workspace> {
+        self.workspaces.get(index)
+    }
+    // Reads everything received since the last call
+    pub fn update(&mut self) {
+        let Some(stream) = &mut self.stream else {
+            return;
+        };
+        let mut chunk = [0u8; 4096];
+        loop {
+            match stream.read(&mut chunk) {
+                Ok(0) => return self.disconnect(),
+                Ok(len) => self.buf.extend_from_slice(&chunk[..len]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    println!("Failed to read from the workspace socket: {err}");
+                    return self.disconnect();
+                }
+            }
+        }
+        let mut refresh = false;
+        while self.buf.len() >= HEADER_LEN {
+            let Some((msg_type, len)) = i3_ipc::decode_header(self.buf[..HEADER_LEN].try_into().unwrap()) else {
+                println!("Invalid message on the workspace socket");
+                return self.disconnect();
+            };
+            if self.buf.len() < HEADER_LEN + len {
+                break;
+            }
+            let msg = self.buf.drain(..HEADER_LEN + len).collect::<Vec<_>>();
+            if msg_type & EVENT_BIT != 0 {
+                // the events only carry the workspaces that changed, so the whole list is asked for again
+                refresh = true;
+            } else if msg_type == GET_WORKSPACES {
+                match serde_json::from_slice(&msg[HEADER_LEN..]) {
+                    Ok(workspaces) => self.workspaces = workspaces,
+                    Err(err) => println!("Failed to parse the workspace list: {err}")
+                }
+            }
+        }
+        if refresh {
+            self.send(GET_WORKSPACES, "");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::{self, Read}, os::unix::net::UnixStream};
+    use nix::{errno::Errno, sys::epoll::{Epoll, EpollCreateFlags}};
+    use crate::i3_ipc::{self, GET_WORKSPACES, SUBSCRIBE};
+    use super::Workspaces;
+
+    #[test]
+    fn connect_subscribes() {
+        let epoll = Epoll::new(EpollCreateFlags::empty()).unwrap();
+        let (stream, mut peer) = UnixStream::pair().unwrap();
+        let mut workspaces = Workspaces::new();
+        workspaces.connect(stream, &epoll).unwrap();
+        let mut expected = i3_ipc::encode(SUBSCRIBE, r#"["workspace"]"#);
+        expected.extend(i3_ipc::encode(GET_WORKSPACES, ""));
+        let mut sent = vec![0; expected.len()];
+        peer.read_exact(&mut sent).unwrap();
+        assert_eq!(sent, expected);
+    }
+
+    #[test]
+    fn connect_reports_write_error() {
+        let epoll = Epoll::new(EpollCreateFlags::empty()).unwrap();
+        let (stream, peer) = UnixStream::pair().unwrap();
+        drop(peer);
+        let mut workspaces = Workspaces::new();
+        let err = workspaces.connect(stream, &epoll).unwrap_err();
+        assert_eq!(err, io::Error::from(Errno::EPIPE).to_string());
+        assert!(workspaces.stream.is_none());
+    }
+}
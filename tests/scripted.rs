@@ -0,0 +1,126 @@
+use std::{
+    fs,
+    io::Read,
+    os::unix::fs::symlink,
+    path::Path,
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+use nix::unistd::{getegid, Group};
+use tempfile::TempDir;
+
+// The daemon exits once the timeline is over, unless it crashed and waits for SIGTERM
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+fn write(path: &Path, contents: &str) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, contents).unwrap();
+}
+
+// Waits until the daemon sent the given key event, or gave up on it
+fn wait_for_key(keys: &Path, key: &str) {
+    let start = Instant::now();
+    while start.elapsed() < TIMEOUT {
+        if fs::read_to_string(keys).is_ok_and(|keys| keys.lines().any(|line| line == key)) {
+            return;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+// Runs the daemon on an offscreen bar with the shipped config, calls during while it
+// runs with the root its own files are under and the file the keys go to, and returns
+// the keys it sent
+fn run(timeline: &str, during: impl FnOnce(&Path, &Path)) -> Vec<String> {
+    let dir = TempDir::new().unwrap();
+    let root = dir.path().join("root");
+    fs::create_dir_all(root.join("usr/share")).unwrap();
+    symlink(concat!(env!("CARGO_MANIFEST_DIR"), "/share/tiny-dfr"), root.join("usr/share/tiny-dfr")).unwrap();
+    // the control socket is given to a group we are in, to not need root
+    let group = Group::from_gid(getegid()).unwrap().unwrap().name;
+    write(&root.join("etc/tiny-dfr/config.toml"), &format!("ControlSocketGroup = \"{group}\"\n"));
+
+    let sysfs = dir.path().join("sys");
+    for (device, max, value) in [("appletb_backlight", "255", "128"), ("apple-panel-bl", "1000", "500")] {
+        write(&sysfs.join("class/backlight").join(device).join("max_brightness"), max);
+        write(&sysfs.join("class/backlight").join(device).join("brightness"), value);
+    }
+    write(&sysfs.join("class/power_supply/BAT0/type"), "Battery");
+    write(&sysfs.join("class/power_supply/BAT0/capacity"), "50");
+
+    let script = dir.path().join("timeline.json");
+    let keys = dir.path().join("keys");
+    write(&script, timeline);
+    let mut daemon = Command::new(env!("CARGO_BIN_EXE_tiny-dfr"))
+        .arg("--offscreen-shm").arg(dir.path().join("bar"))
+        .arg("--script").arg(&script)
+        .arg("--record-keys").arg(&keys)
+        .env("TINY_DFR_ROOT", &root)
+        .env("TINY_DFR_SYSFS_ROOT", &sysfs)
+        .env("DBUS_SYSTEM_BUS_ADDRESS", format!("unix:path={}", dir.path().join("no-bus").display()))
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    during(&root, &keys);
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = daemon.try_wait().unwrap() {
+            break Some(status);
+        }
+        if start.elapsed() > TIMEOUT {
+            daemon.kill().unwrap();
+            daemon.wait().unwrap();
+            break None;
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    let mut output = String::new();
+    daemon.stdout.take().unwrap().read_to_string(&mut output).unwrap();
+    daemon.stderr.take().unwrap().read_to_string(&mut output).unwrap();
+    assert!(status.is_some_and(|s| s.success()), "the daemon did not exit once the timeline was over:\n{output}");
+    fs::read_to_string(keys).unwrap().lines().map(String::from).collect()
+}
+
+#[test]
+fn touch_sends_key() {
+    let keys = run(r#"[
+        { "at": 0, "type": "touch_down", "slot": 0, "x": 300, "y": 30 },
+        { "at": 50, "type": "touch_up", "slot": 0 },
+        { "at": 100, "type": "activity" }
+    ]"#, |_, _| {});
+    assert_eq!(keys, ["F2 1", "F2 0"]);
+}
+
+#[test]
+fn touch_with_fn_held_sends_media_key() {
+    // the tenth of twelve buttons on the media layer
+    let keys = run(r#"[
+        { "at": 0, "type": "key", "key": "Fn", "pressed": true },
+        { "at": 100, "type": "touch_down", "slot": 0, "x": 1590, "y": 30 },
+        { "at": 150, "type": "touch_up", "slot": 0 },
+        { "at": 200, "type": "key", "key": "Fn", "pressed": false },
+        { "at": 250, "type": "activity" }
+    ]"#, |_, _| {});
+    assert_eq!(keys, ["Mute 1", "Mute 0"]);
+}
+
+#[test]
+fn reload_adds_keys() {
+    // the first button becomes one sending a key that no button sent before
+    let keys = run(r#"[
+        { "at": 0, "type": "touch_down", "slot": 0, "x": 100, "y": 30 },
+        { "at": 50, "type": "touch_up", "slot": 0 },
+        { "at": 2000, "type": "touch_down", "slot": 0, "x": 100, "y": 30 },
+        { "at": 2050, "type": "touch_up", "slot": 0 },
+        { "at": 2100, "type": "activity" }
+    ]"#, |root, keys| {
+        wait_for_key(keys, "F1 0");
+        let config = root.join("etc/tiny-dfr/config.toml");
+        let mut text = fs::read_to_string(&config).unwrap();
+        text.push_str("[Layers]\nfkeys = [{ Text = \"F13\", Action = \"F13\" }, { Text = \"F2\", Action = \"F2\" }]\n");
+        fs::write(config, text).unwrap();
+    });
+    assert_eq!(keys, ["F1 1", "F1 0", "F13 1", "F13 0"]);
+}